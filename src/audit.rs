@@ -0,0 +1,262 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Security audit log of authentication events, kept apart from operational
+// logs. Each event is a single JSON object; fields are only ever added to
+// the schema, `version` being bumped on incompatible changes.
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use structopt::StructOpt;
+use url::Url;
+
+const SCHEMA_VERSION: u32 = 1;
+
+// LOG_AUTHPRIV
+const SYSLOG_FACILITY: u8 = 10;
+const SYSLOG_TAG: &str = "hydra-idp-ldap";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkUrl {
+    None,
+    Stdout,
+    // `file:///var/log/hydra-idp-ldap/audit.log`
+    File(PathBuf),
+    // `syslog:///dev/log`
+    Syslog(PathBuf),
+}
+
+impl FromStr for SinkUrl {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => return Ok(SinkUrl::None),
+            "stdout" => return Ok(SinkUrl::Stdout),
+            "syslog" => return Ok(SinkUrl::Syslog(PathBuf::from("/dev/log"))),
+            _ => {}
+        }
+
+        let url = Url::parse(value).map_err(|e| format!("invalid audit sink URL: {}", e))?;
+
+        match url.scheme() {
+            "file" => Ok(SinkUrl::File(PathBuf::from(url.path()))),
+            "syslog" => Ok(SinkUrl::Syslog(PathBuf::from(url.path()))),
+            _ => Err(format!("unknown audit sink: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "audit.sink",
+        long = "audit.sink",
+        env = "AUDIT_SINK",
+        hide_env_values = true,
+        value_name = "url",
+        default_value = "none",
+        help = "Where to write the audit log (`none`, `stdout`, `file://<path>` or \
+                `syslog://<socket path>`)",
+        display_order = 13
+    )]
+    sink: SinkUrl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Login,
+    Lockout,
+    Consent,
+    Logout,
+    PasswordChange,
+    PasswordResetRequest,
+    PasswordReset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    version: u32,
+    timestamp: String,
+    event: Kind,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<&'a str>,
+    // Login, consent or logout challenge of the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge: Option<&'a str>,
+    // A fixed code such as `invalid_credentials`, never free text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scopes: Option<&'a [String]>,
+    // Names of the claims released, never their values.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claims: Option<Vec<&'a str>>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(event: Kind, outcome: Outcome) -> Event<'a> {
+        Event {
+            version: SCHEMA_VERSION,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+            outcome,
+            source_ip: None,
+            user_agent: None,
+            login: None,
+            subject: None,
+            client_id: None,
+            challenge: None,
+            reason: None,
+            scopes: None,
+            claims: None,
+        }
+    }
+
+    pub fn source(mut self, ip: IpAddr, user_agent: Option<&'a str>) -> Self {
+        self.source_ip = Some(ip);
+        self.user_agent = user_agent;
+        self
+    }
+
+    pub fn login(mut self, login: &'a str) -> Self {
+        self.login = Some(login);
+        self
+    }
+
+    pub fn subject(mut self, subject: &'a str) -> Self {
+        self.subject = Some(subject);
+        self
+    }
+
+    pub fn client_id(mut self, client_id: &'a str) -> Self {
+        self.client_id = Some(client_id);
+        self
+    }
+
+    pub fn challenge(mut self, challenge: &'a str) -> Self {
+        self.challenge = Some(challenge);
+        self
+    }
+
+    pub fn reason(mut self, reason: &'a str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    pub fn scopes(mut self, scopes: &'a [String]) -> Self {
+        self.scopes = Some(scopes);
+        self
+    }
+
+    pub fn claims<I: IntoIterator<Item = &'a String>>(mut self, claims: I) -> Self {
+        let mut claims: Vec<&str> = claims.into_iter().map(|c| c.as_str()).collect();
+        claims.sort_unstable();
+        self.claims = Some(claims);
+        self
+    }
+}
+
+enum Sink {
+    None,
+    Stdout,
+    File(Mutex<File>),
+    Syslog(UnixDatagram, PathBuf),
+}
+
+pub struct Audit {
+    sink: Sink,
+}
+
+impl Audit {
+    pub fn new(opts: Opts) -> io::Result<Audit> {
+        let sink = match opts.sink {
+            SinkUrl::None => Sink::None,
+            SinkUrl::Stdout => Sink::Stdout,
+            SinkUrl::File(path) => Sink::File(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            SinkUrl::Syslog(path) => Sink::Syslog(UnixDatagram::unbound()?, path),
+        };
+
+        Ok(Audit { sink })
+    }
+
+    pub fn record(&self, event: Event) {
+        if let Sink::None = self.sink {
+            return;
+        }
+
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Unable to serialize audit event: {}", e);
+                return;
+            }
+        };
+
+        let result = match &self.sink {
+            Sink::None => Ok(()),
+            Sink::Stdout => writeln!(io::stdout(), "{}", line),
+            Sink::File(file) => {
+                writeln!(file.lock().unwrap_or_else(|e| e.into_inner()), "{}", line)
+            }
+            Sink::Syslog(socket, path) => {
+                // LOG_INFO for successes, LOG_WARNING for failures.
+                let severity = match event.outcome {
+                    Outcome::Success => 6,
+                    Outcome::Failure => 4,
+                };
+                let message = format!(
+                    "<{}>{}[{}]: {}",
+                    SYSLOG_FACILITY * 8 + severity,
+                    SYSLOG_TAG,
+                    std::process::id(),
+                    line
+                );
+
+                socket.send_to(message.as_bytes(), path).map(|_| ())
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Unable to write audit event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Configuration file support. Options are grouped in sections named after
+// the part of their name before the dot, e.g. `--ldap.url` is `url` in the
+// `ldap` section:
+//
+//     [ldap]
+//     url = ["ldap://ldap1.example.org", "ldap://ldap2.example.org"]
+//
+//     [oauth.attrs-map]
+//     cn = "name"
+//     mail = "email"
+//
+// Values read from the file are passed to the command line parser as if they
+// were arguments, unless the option is already set on the command line or in
+// the environment, so that both take precedence over the file.
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use structopt::clap::{App, ArgMatches};
+use thiserror::Error;
+use url::Url;
+
+const CONFIG_OPTION: &str = "--config";
+const CONFIG_ENV: &str = "CONFIG_FILE";
+
+// Options whose value is never printed.
+const SECRETS: &[&str] = &[
+    "csrf.secret",
+    "ldap.bind-pw",
+    "oauth.subject-pairwise-secret",
+    "reset.secret",
+    "smtp.password",
+];
+const REDACTED: &str = "REDACTED";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read {0}: {1}")]
+    ReadError(String, io::Error),
+
+    #[error("unable to parse {0}: {1}")]
+    TomlError(String, toml::de::Error),
+
+    #[error("unable to parse {0}: {1}")]
+    YamlError(String, serde_yaml::Error),
+
+    #[error("unsupported format for {0} (expected a .toml, .yaml or .yml file)")]
+    UnknownFormat(String),
+
+    #[error("invalid configuration in {0}: {1}")]
+    InvalidConfig(String, String),
+
+    #[error("unable to serialize configuration: {0}")]
+    SerializeError(#[from] toml::ser::Error),
+}
+
+// The command line arguments, with the options read from the configuration
+// file inserted after the program name.
+pub fn args<I: IntoIterator<Item = OsString>>(args: I) -> Result<Vec<OsString>, Error> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+
+    let path = match config_path(&args) {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+
+    let file_args: Vec<OsString> = load(path.as_str())?
+        .into_iter()
+        .filter(|(name, _)| !is_set(&args, name))
+        .map(|(name, value)| OsString::from(format!("--{}={}", name, value)))
+        .collect();
+
+    let mut all_args: Vec<OsString> = args.drain(..args.len().min(1)).collect();
+    all_args.extend(file_args);
+    all_args.extend(args);
+
+    Ok(all_args)
+}
+
+// The effective configuration of `app`, in the configuration file format.
+pub fn effective(app: App, matches: &ArgMatches) -> Result<String, Error> {
+    let mut sections: BTreeMap<String, BTreeMap<String, toml::Value>> = BTreeMap::new();
+
+    for (name, takes_value) in options(app) {
+        let pos = match name.find('.') {
+            Some(pos) => pos,
+            None => continue,
+        };
+
+        let value = match takes_value {
+            true => {
+                // Options with neither a value nor a default one are left
+                // out.
+                let mut values: Vec<toml::Value> = match matches.values_of_lossy(&name) {
+                    Some(values) => values
+                        .iter()
+                        .map(|value| toml::Value::String(redact(&name, value)))
+                        .collect(),
+                    None => continue,
+                };
+
+                match values.len() {
+                    1 => values.remove(0),
+                    _ => toml::Value::Array(values),
+                }
+            }
+            false => toml::Value::Boolean(matches.is_present(&name)),
+        };
+
+        sections
+            .entry(name[..pos].to_string())
+            .or_default()
+            .insert(name[pos + 1..].to_string(), value);
+    }
+
+    Ok(toml::to_string(&sections)?)
+}
+
+// The long options of `app`, with whether they take a value. clap doesn’t
+// expose its arguments, they are read from the help message which lists
+// every option on its own line (`--ldap.url <url>...`).
+fn options(mut app: App) -> Vec<(String, bool)> {
+    let mut help = vec![];
+    if app.write_long_help(&mut help).is_err() {
+        return vec![];
+    }
+
+    String::from_utf8_lossy(&help)
+        .lines()
+        .filter_map(|line| {
+            let option = line.trim_start().strip_prefix("--")?;
+            let name = option.split_whitespace().next()?;
+            let takes_value = option[name.len()..].trim_start().starts_with('<');
+
+            Some((name.to_string(), takes_value))
+        })
+        .collect()
+}
+
+// `--config <file>`, `--config=<file>` or the `CONFIG_FILE` environment
+// variable.
+fn config_path(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_OPTION {
+            return args.next().map(|path| path.into_owned());
+        }
+
+        if let Some(path) = arg.strip_prefix(&format!("{}=", CONFIG_OPTION)) {
+            return Some(path.to_string());
+        }
+    }
+
+    env::var(CONFIG_ENV).ok().filter(|path| !path.is_empty())
+}
+
+// Whether an option is set on the command line or in the environment.
+fn is_set(args: &[OsString], name: &str) -> bool {
+    let option = format!("--{}", name);
+    let option_with_value = format!("{}=", option);
+
+    let in_args = args.iter().skip(1).any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == option || arg.starts_with(&option_with_value)
+    });
+
+    in_args || env::var_os(env_name(name)).is_some()
+}
+
+// `ldap.bind-dn` is read from `LDAP_BIND_DN`.
+fn env_name(name: &str) -> String {
+    name.to_uppercase().replace(|c| c == '.' || c == '-', "_")
+}
+
+fn load(path: &str) -> Result<Vec<(String, String)>, Error> {
+    let content = fs::read_to_string(path).map_err(|e| Error::ReadError(path.to_string(), e))?;
+
+    let root: Value = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("toml") => {
+            toml::from_str(&content).map_err(|e| Error::TomlError(path.to_string(), e))?
+        }
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(&content).map_err(|e| Error::YamlError(path.to_string(), e))?
+        }
+        _ => return Err(Error::UnknownFormat(path.to_string())),
+    };
+
+    let invalid = |message: String| Error::InvalidConfig(path.to_string(), message);
+
+    let sections = match root {
+        Value::Object(sections) => sections,
+        // An empty YAML document.
+        Value::Null => return Ok(vec![]),
+        _ => return Err(invalid("expected a map of sections".to_string())),
+    };
+
+    let mut options: Vec<(String, String)> = vec![];
+
+    for (section, section_options) in sections {
+        let section_options = match section_options {
+            Value::Object(section_options) => section_options,
+            _ => return Err(invalid(format!("`{}` must be a section", section))),
+        };
+
+        for (option, value) in section_options {
+            let name = format!("{}.{}", section, option);
+
+            if let Some(value) =
+                option_value(&value).map_err(|e| invalid(format!("`{}` {}", name, e)))?
+            {
+                options.push((name, value));
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+// Lists are comma separated and maps are comma separated `<key>:<value>`
+// pairs, like on the command line. Items containing the separators would be
+// split differently than written, they are rejected.
+fn option_value(value: &Value) -> Result<Option<String>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(scalar)
+                .collect::<Result<Vec<String>, String>>()?;
+
+            if let Some(item) = items.iter().find(|item| item.contains(',')) {
+                return Err(format!("items can’t contain `,`: `{}`", item));
+            }
+
+            Ok(Some(items.join(",")))
+        }
+        Value::Object(items) => {
+            let mut pairs = vec![];
+
+            for (key, value) in items {
+                let value = scalar(value)?;
+
+                if key.contains(|c| c == ',' || c == ':') || value.contains(',') {
+                    return Err(format!(
+                        "keys can’t contain `,` or `:` and values `,`: `{}`",
+                        key
+                    ));
+                }
+
+                pairs.push(format!("{}:{}", key, value));
+            }
+
+            Ok(Some(pairs.join(",")))
+        }
+        _ => scalar(value).map(Some),
+    }
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err("must be a string, a number, a boolean, a list or a map of those".to_string()),
+    }
+}
+
+// Secret options are hidden, as well as passwords in URLs (e.g. the Redis
+// throttling backend).
+fn redact(name: &str, value: &str) -> String {
+    if SECRETS.contains(&name) {
+        return REDACTED.to_string();
+    }
+
+    match Url::parse(value) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED));
+            url.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use structopt::clap::Arg;
+
+    // Write `content` to a temporary file with the given extension.
+    fn write_config(extension: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("config-{}.{}", uuid::Uuid::new_v4(), extension));
+        fs::write(&path, content).unwrap();
+
+        path.to_string_lossy().into_owned()
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn loads_toml() {
+        let path = write_config(
+            "toml",
+            r#"
+                [ldap]
+                url = ["ldap://ldap1.example.org", "ldap://ldap2.example.org"]
+                pool-size = 10
+
+                [oauth.attrs-map]
+                cn = "name"
+                mail = "email"
+            "#,
+        );
+
+        let mut options = load(path.as_str()).unwrap();
+        options.sort();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            options,
+            vec![
+                ("ldap.pool-size".to_string(), "10".to_string()),
+                (
+                    "ldap.url".to_string(),
+                    "ldap://ldap1.example.org,ldap://ldap2.example.org".to_string()
+                ),
+                (
+                    "oauth.attrs-map".to_string(),
+                    "cn:name,mail:email".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_yaml() {
+        let path = write_config(
+            "yaml",
+            "ldap:\n  url: ldap://ldap.example.org\n  start-tls: true\nsmtp:\n  password: ~\n",
+        );
+
+        let mut options = load(path.as_str()).unwrap();
+        options.sort();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            options,
+            vec![
+                ("ldap.start-tls".to_string(), "true".to_string()),
+                (
+                    "ldap.url".to_string(),
+                    "ldap://ldap.example.org".to_string()
+                ),
+            ]
+        );
+
+        let path = write_config("yml", "~\n");
+        assert_eq!(load(path.as_str()).unwrap(), vec![]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let path = write_config("json", "{}");
+        assert!(matches!(load(path.as_str()), Err(Error::UnknownFormat(_))));
+        fs::remove_file(path).unwrap();
+
+        let path = write_config("toml", "url = \"ldap://ldap.example.org\"");
+        assert!(matches!(
+            load(path.as_str()),
+            Err(Error::InvalidConfig(_, _))
+        ));
+        fs::remove_file(path).unwrap();
+
+        let path = write_config("yaml", "ldap: [");
+        assert!(matches!(load(path.as_str()), Err(Error::YamlError(_, _))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_separators_in_values() {
+        assert_eq!(option_value(&json!("a,b")), Ok(Some("a,b".to_string())));
+        assert_eq!(option_value(&json!(["a", 1])), Ok(Some("a,1".to_string())));
+        assert_eq!(
+            option_value(&json!({"cn": "ldap://x"})),
+            Ok(Some("cn:ldap://x".to_string()))
+        );
+
+        assert!(option_value(&json!(["a,b"])).is_err());
+        assert!(option_value(&json!({"a:b": "c"})).is_err());
+        assert!(option_value(&json!({"a,b": "c"})).is_err());
+        assert!(option_value(&json!({"a": "b,c"})).is_err());
+        assert!(option_value(&json!([["a"]])).is_err());
+        assert!(option_value(&json!({"a": {"b": "c"}})).is_err());
+    }
+
+    #[test]
+    fn gives_precedence_to_arguments_and_environment() {
+        let path = write_config(
+            "toml",
+            "[test]\nfrom-file = \"file\"\nfrom-args = \"file\"\nfrom-env = \"file\"\n",
+        );
+        env::set_var("TEST_FROM_ENV", "env");
+
+        let args = args(os_args(&[
+            "hydra-idp-ldap",
+            "--config",
+            path.as_str(),
+            "--test.from-args=args",
+        ]))
+        .unwrap();
+
+        env::remove_var("TEST_FROM_ENV");
+        fs::remove_file(path.as_str()).unwrap();
+
+        assert_eq!(
+            args,
+            os_args(&[
+                "hydra-idp-ldap",
+                "--test.from-file=file",
+                "--config",
+                path.as_str(),
+                "--test.from-args=args",
+            ])
+        );
+    }
+
+    #[test]
+    fn finds_config_path() {
+        assert_eq!(
+            config_path(&os_args(&["bin", "--config=a.toml"])),
+            Some("a.toml".to_string())
+        );
+        assert_eq!(
+            config_path(&os_args(&["bin", "--config", "a.toml"])),
+            Some("a.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        assert_eq!(redact("ldap.bind-pw", "secret"), REDACTED);
+        assert_eq!(redact("ldap.bind-dn", "cn=admin"), "cn=admin");
+        assert_eq!(
+            redact("throttle.backend", "redis://:secret@redis:6379"),
+            "redis://:REDACTED@redis:6379"
+        );
+        assert_eq!(
+            redact("throttle.backend", "redis://redis:6379"),
+            "redis://redis:6379"
+        );
+    }
+
+    #[test]
+    fn prints_effective_configuration() {
+        let app = || {
+            App::new("test")
+                .arg(Arg::with_name("config").long("config").takes_value(true))
+                .arg(
+                    Arg::with_name("ldap.url")
+                        .long("ldap.url")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .default_value("ldap://localhost"),
+                )
+                .arg(
+                    Arg::with_name("ldap.bind-pw")
+                        .long("ldap.bind-pw")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reset.secret")
+                        .long("reset.secret")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("web.flag").long("web.flag"))
+        };
+
+        let matches = app().get_matches_from(vec![
+            "test",
+            "--ldap.url=ldap://a,ldap://b",
+            "--reset.secret=secret",
+        ]);
+
+        let config: toml::Value = toml::from_str(&effective(app(), &matches).unwrap()).unwrap();
+
+        assert_eq!(
+            config,
+            toml::from_str(
+                r#"
+                    [ldap]
+                    url = ["ldap://a", "ldap://b"]
+
+                    [reset]
+                    secret = "REDACTED"
+
+                    [web]
+                    flag = false
+                "#
+            )
+            .unwrap()
+        );
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Thin wrapper around `hydra_client::Hydra` for the parts of the Hydra admin
+// API the client library doesn’t (fully) expose. Everything else is reachable
+// through `Deref`.
+
+use hydra_client::{ApiError, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::time::Duration;
+use url::Url;
+
+use crate::metrics;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OAuth2Client {
+    pub client_id: String,
+
+    #[serde(default)]
+    pub client_name: String,
+
+    #[serde(default)]
+    pub client_uri: String,
+
+    #[serde(default)]
+    pub logo_uri: String,
+
+    #[serde(default)]
+    pub policy_uri: String,
+
+    #[serde(default)]
+    pub tos_uri: String,
+
+    #[serde(default)]
+    pub metadata: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OidcContext {
+    #[serde(default)]
+    pub login_hint: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    #[serde(default)]
+    pub client: OAuth2Client,
+
+    #[serde(default)]
+    pub context: HashMap<String, Value>,
+
+    #[serde(default)]
+    pub oidc_context: OidcContext,
+
+    // The authorization request the client sent, for the parameters Hydra
+    // doesn’t expose otherwise.
+    #[serde(default)]
+    pub request_url: String,
+
+    pub skip: bool,
+
+    pub subject: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsentRequest {
+    #[serde(default)]
+    pub client: OAuth2Client,
+
+    #[serde(default)]
+    pub context: HashMap<String, Value>,
+
+    #[serde(default)]
+    pub requested_access_token_audience: Vec<String>,
+
+    #[serde(default)]
+    pub requested_scope: Vec<String>,
+
+    pub skip: bool,
+
+    pub subject: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogoutRequest {
+    #[serde(default)]
+    pub subject: String,
+}
+
+// Why a login or consent request is rejected, sent back to the client as an
+// OAuth 2.0 error.
+#[derive(Debug, Serialize)]
+pub struct RejectRequest {
+    pub error: String,
+    pub error_description: String,
+    pub status_code: u16,
+}
+
+impl RejectRequest {
+    pub fn new(error: &str, error_description: &str, status_code: u16) -> RejectRequest {
+        RejectRequest {
+            error: error.to_string(),
+            error_description: error_description.to_string(),
+            status_code,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletedRequest {
+    pub redirect_to: String,
+}
+
+pub struct Hydra {
+    inner: hydra_client::Hydra,
+    url: Url,
+    // The public server, which browsers are sent to.
+    public_url: Option<Url>,
+    client: reqwest::blocking::Client,
+}
+
+impl Hydra {
+    pub fn new(url: Url, public_url: Option<Url>) -> Hydra {
+        Hydra {
+            inner: hydra_client::Hydra::new(url.clone()),
+            url: base_url(url),
+            public_url: public_url.map(base_url),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    // Health
+
+    // Hydra’s own readiness probe, checking its database.
+    pub fn check(&self, timeout: Duration) -> Result<(), Error> {
+        let _timer = metrics::hydra_timer("health");
+
+        let r = self
+            .client
+            .get(self.endpoint("/health/ready")?)
+            .timeout(timeout)
+            .send()?;
+
+        match r.status().is_success() {
+            true => Ok(()),
+            false => Err(Error::UnknownError(format!(
+                "Hydra is not ready (status: {})",
+                r.status()
+            ))),
+        }
+    }
+
+    // Login
+
+    pub fn get_login_request(&self, login_challenge: String) -> Result<LoginRequest, Error> {
+        self.get(
+            "get_login_request",
+            self.endpoint("/oauth2/auth/requests/login")?,
+            &[("login_challenge", login_challenge.as_str())],
+        )
+    }
+
+    pub fn reject_login_request(
+        &self,
+        login_challenge: String,
+        body: &RejectRequest,
+    ) -> Result<CompletedRequest, Error> {
+        self.put(
+            "reject_login_request",
+            self.endpoint("/oauth2/auth/requests/login/reject")?,
+            &[("login_challenge", login_challenge.as_str())],
+            body,
+        )
+    }
+
+    // Consent
+
+    pub fn get_consent_request(&self, consent_challenge: String) -> Result<ConsentRequest, Error> {
+        self.get(
+            "get_consent_request",
+            self.endpoint("/oauth2/auth/requests/consent")?,
+            &[("consent_challenge", consent_challenge.as_str())],
+        )
+    }
+
+    pub fn reject_consent_request(
+        &self,
+        consent_challenge: String,
+        body: &RejectRequest,
+    ) -> Result<CompletedRequest, Error> {
+        self.put(
+            "reject_consent_request",
+            self.endpoint("/oauth2/auth/requests/consent/reject")?,
+            &[("consent_challenge", consent_challenge.as_str())],
+            body,
+        )
+    }
+
+    // Logout
+
+    pub fn get_logout_request(&self, logout_challenge: String) -> Result<LogoutRequest, Error> {
+        self.get(
+            "get_logout_request",
+            self.endpoint("/oauth2/auth/requests/logout")?,
+            &[("logout_challenge", logout_challenge.as_str())],
+        )
+    }
+
+    // Where to send the browser to end its session, only known when the
+    // public URL is set.
+    pub fn browser_logout_url(&self) -> Option<Url> {
+        self.public_url
+            .as_ref()?
+            .join("oauth2/sessions/logout")
+            .ok()
+    }
+
+    // Whether `url` is served by the public server.
+    pub fn is_public_url(&self, url: &Url) -> bool {
+        match &self.public_url {
+            Some(public_url) => public_url.origin() == url.origin(),
+            None => false,
+        }
+    }
+
+    // Time a call made with the client library.
+    pub fn timed<T, F>(&self, operation: &str, f: F) -> T
+    where
+        F: FnOnce(&hydra_client::Hydra) -> T,
+    {
+        let _timer = metrics::hydra_timer(operation);
+        f(&self.inner)
+    }
+
+    // Internal
+
+    // Endpoints are joined as relative paths so that a path prefix in the
+    // URL, e.g. behind a reverse proxy, is kept.
+    fn endpoint(&self, endpoint: &str) -> Result<Url, Error> {
+        self.url
+            .join(endpoint.trim_start_matches('/'))
+            .map_err(Error::URLParseError)
+    }
+
+    fn deserialize<R: for<'de> Deserialize<'de>>(
+        r: reqwest::blocking::Response,
+    ) -> Result<R, Error> {
+        let status = r.status();
+
+        if status.is_success() {
+            r.json().map_err(Error::RequestError)
+        } else {
+            match r.json::<ApiError>() {
+                Ok(api_error) => Err(Error::ApiError(api_error)),
+                Err(_) => Err(Error::UnknownError(format!(
+                    "unable to parse reply from Hydra API (status: {})",
+                    status
+                ))),
+            }
+        }
+    }
+
+    fn get<R: for<'de> Deserialize<'de>>(
+        &self,
+        operation: &str,
+        url: Url,
+        query: &[(&str, &str)],
+    ) -> Result<R, Error> {
+        let _timer = metrics::hydra_timer(operation);
+        let r = self.client.get(url).query(query).send()?;
+
+        Hydra::deserialize(r)
+    }
+
+    fn put<R: for<'de> Deserialize<'de>, B: Serialize>(
+        &self,
+        operation: &str,
+        url: Url,
+        query: &[(&str, &str)],
+        body: &B,
+    ) -> Result<R, Error> {
+        let _timer = metrics::hydra_timer(operation);
+        let r = self.client.put(url).query(query).json(body).send()?;
+
+        Hydra::deserialize(r)
+    }
+}
+
+impl Deref for Hydra {
+    type Target = hydra_client::Hydra;
+
+    fn deref(&self) -> &hydra_client::Hydra {
+        &self.inner
+    }
+}
+
+// `url` with a trailing slash, relative paths being joined after the last
+// slash.
+fn base_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(url: &str, endpoint: &str) -> String {
+        Hydra::new(url.parse().unwrap(), None)
+            .endpoint(endpoint)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn joins_endpoints() {
+        assert_eq!(
+            endpoint("http://hydra:4445", "/oauth2/auth/requests/login"),
+            "http://hydra:4445/oauth2/auth/requests/login"
+        );
+        assert_eq!(
+            endpoint("http://hydra:4445/", "/health/ready"),
+            "http://hydra:4445/health/ready"
+        );
+    }
+
+    #[test]
+    fn keeps_path_prefix() {
+        assert_eq!(
+            endpoint("https://example.org/hydra", "/oauth2/auth/requests/login"),
+            "https://example.org/hydra/oauth2/auth/requests/login"
+        );
+        assert_eq!(
+            endpoint("https://example.org/hydra/", "/health/ready"),
+            "https://example.org/hydra/health/ready"
+        );
+    }
+
+    #[test]
+    fn builds_browser_logout_url() {
+        let hydra = Hydra::new(
+            "http://hydra:4445".parse().unwrap(),
+            Some("https://example.org/hydra".parse().unwrap()),
+        );
+
+        assert_eq!(
+            hydra.browser_logout_url().unwrap().as_str(),
+            "https://example.org/hydra/oauth2/sessions/logout"
+        );
+        assert!(hydra.is_public_url(&"https://example.org/oauth2/auth?a=b".parse().unwrap()));
+        assert!(!hydra.is_public_url(&"https://example.com/oauth2/auth".parse().unwrap()));
+
+        let hydra = Hydra::new("http://hydra:4445".parse().unwrap(), None);
+        assert_eq!(hydra.browser_logout_url(), None);
+        assert!(!hydra.is_public_url(&"http://hydra:4445/".parse().unwrap()));
+    }
+}
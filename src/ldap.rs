@@ -13,14 +13,46 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use ldap3::{LdapConn, LdapError, ResultEntry, Scope, SearchEntry};
+use ldap3::exop::{PasswordModify, WhoAmI, WhoAmIResp};
+use ldap3::{
+    ExopResult, LdapConn, LdapConnSettings, LdapError, LdapResult, Mod, ResultEntry, Scope,
+    SearchEntry,
+};
+use regex::Regex;
 use serde_json::json;
 use serde_json::value::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 use structopt::StructOpt;
 use thiserror::Error;
 use url::Url;
 
+use crate::metrics;
+use crate::parse;
+
+mod ad;
+mod attrs;
+mod dn;
+mod filter;
+mod groups;
+mod pool;
+mod ppolicy;
+mod profile;
+mod servers;
+mod tls;
+
+use attrs::Cardinalities;
+use dn::BindTemplate;
+use filter::FilterTemplate;
+use groups::{Mapping, Nested, Source};
+use pool::{Pool, PoolSettings, PooledConn};
+use servers::{Servers, Strategy};
+
+pub use ppolicy::Warning as PasswordWarning;
+pub use profile::Profile;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -31,20 +63,242 @@ pub enum Error {
 
     #[error("invalid credentials")]
     InvalidCredentials,
+
+    #[error("login not permitted at this time or from this workstation")]
+    LoginRestricted,
+
+    #[error("password expired")]
+    PasswordExpired,
+
+    #[error("password must be changed")]
+    PasswordMustChange,
+
+    // Unlike `PasswordMustChange`, the bind failed so the user can’t change
+    // their password with it (e.g. Active Directory’s `pwdLastSet` set to 0).
+    #[error("password must be changed and can’t be used to bind")]
+    PasswordMustReset,
+
+    #[error("account disabled")]
+    AccountDisabled,
+
+    #[error("account expired")]
+    AccountExpired,
+
+    #[error("account locked")]
+    AccountLocked,
+
+    #[error("{0}")]
+    PasswordRejected(String),
+
+    #[error("{0}")]
+    InvalidFilter(String),
+
+    #[error("timed out waiting for a connection from the pool")]
+    PoolTimeout,
+
+    #[error("LDAP pool size must be greater than 0")]
+    InvalidPoolSize,
+
+    #[error(transparent)]
+    TlsError(#[from] native_tls::Error),
+
+    #[error("unable to load {0}: {1}")]
+    InvalidTlsFile(String, String),
+
+    #[error("StartTLS can’t be used with an ldaps:// URL")]
+    StartTlsOverLdaps,
+
+    #[error("no LDAP server available")]
+    NoServerAvailable,
+
+    #[error("nested groups can’t be resolved when reading groups from memberOf")]
+    NestedMemberOf,
+
+    #[error("a bind DN and password are required when no user bind template is set")]
+    MissingBindDn,
+}
+
+impl Error {
+    // Whether the error means the server is unreachable or unable to serve
+    // requests, in which case another server may be tried.
+    pub fn is_server_failure(&self) -> bool {
+        match self {
+            Error::LdapError(LdapError::LdapResult { result }) => {
+                // LDAP_BUSY, LDAP_UNAVAILABLE
+                result.rc == 51 || result.rc == 52
+            }
+            Error::LdapError(_) => true,
+            Error::NoServerAvailable => true,
+            _ => false,
+        }
+    }
+
+    // Code of the error in the audit log, which never includes the details
+    // of the error.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::UserNotFound(_) => "user_not_found",
+            Error::InvalidCredentials => "invalid_credentials",
+            Error::LoginRestricted => "login_restricted",
+            Error::PasswordExpired => "password_expired",
+            Error::PasswordMustChange => "password_must_change",
+            Error::PasswordMustReset => "password_must_reset",
+            Error::AccountDisabled => "account_disabled",
+            Error::AccountExpired => "account_expired",
+            Error::AccountLocked => "account_locked",
+            Error::PasswordRejected(_) => "password_rejected",
+            _ => "ldap_error",
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
 pub struct Opts {
+    #[structopt(
+        name = "ldap.profile",
+        long = "ldap.profile",
+        env = "LDAP_PROFILE",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["default", "active-directory"],
+        default_value = "default",
+        help = "Kind of LDAP server, used as defaults for the users and groups filters, nested \
+                groups and subject (`active-directory` logs users in with their sAMAccountName \
+                or userPrincipalName, uses objectGUID as the subject and resolves nested groups)",
+        display_order = 39
+    )]
+    profile: Profile,
+
     #[structopt(
         name = "ldap.url",
         long = "ldap.url",
         env = "LDAP_URL",
         hide_env_values = true,
         value_name = "url",
-        help = "URL to the LDAP server (example: ldap://ldap.example.org:389)",
+        required = true,
+        use_delimiter = true,
+        help = "Comma separated list of URLs to LDAP servers (example: \
+                ldap://ldap1.example.org:389,ldap://ldap2.example.org:389)",
         display_order = 40
     )]
-    url: Url,
+    urls: Vec<Url>,
+
+    #[structopt(
+        name = "ldap.url-strategy",
+        long = "ldap.url-strategy",
+        env = "LDAP_URL_STRATEGY",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["failover", "round-robin"],
+        default_value = "failover",
+        help = "How to choose between LDAP servers (`failover` tries them in the given order, \
+                `round-robin` starts with a different server for each connection)",
+        display_order = 40
+    )]
+    url_strategy: Strategy,
+
+    #[structopt(
+        name = "ldap.timeout",
+        long = "ldap.timeout",
+        env = "LDAP_TIMEOUT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "5",
+        help = "Time in seconds after which connecting to or querying an LDAP server fails",
+        display_order = 40
+    )]
+    timeout: u64,
+
+    #[structopt(
+        name = "ldap.circuit-breaker-threshold",
+        long = "ldap.circuit-breaker-threshold",
+        env = "LDAP_CIRCUIT_BREAKER_THRESHOLD",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "3",
+        help = "Number of consecutive failures after which an LDAP server is skipped (0 \
+                disables circuit breaking)",
+        display_order = 40
+    )]
+    circuit_breaker_threshold: u32,
+
+    #[structopt(
+        name = "ldap.circuit-breaker-cooldown",
+        long = "ldap.circuit-breaker-cooldown",
+        env = "LDAP_CIRCUIT_BREAKER_COOLDOWN",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "30",
+        help = "Time in seconds during which a failing LDAP server is skipped",
+        display_order = 40
+    )]
+    circuit_breaker_cooldown: u64,
+
+    #[structopt(
+        name = "ldap.starttls",
+        long = "ldap.starttls",
+        env = "LDAP_STARTTLS",
+        hide_env_values = true,
+        value_name = "bool",
+        parse(try_from_str),
+        default_value = "false",
+        help = "Use the StartTLS operation to secure ldap:// connections",
+        display_order = 40
+    )]
+    starttls: bool,
+
+    #[structopt(
+        name = "ldap.tls-ca-file",
+        long = "ldap.tls-ca-file",
+        env = "LDAP_TLS_CA_FILE",
+        hide_env_values = true,
+        value_name = "file",
+        parse(try_from_str = parse::file),
+        help = "Path to a file containing CA certificates in PEM format used to verify the LDAP \
+                server’s certificate (in addition to the system trust store)",
+        display_order = 40,
+    )]
+    tls_ca_file: Option<String>,
+
+    #[structopt(
+        name = "ldap.tls-cert-file",
+        long = "ldap.tls-cert-file",
+        env = "LDAP_TLS_CERT_FILE",
+        hide_env_values = true,
+        value_name = "file",
+        parse(try_from_str = parse::file),
+        requires = "ldap.tls-key-file",
+        help = "Path to a client certificate in PEM format presented to the LDAP server",
+        display_order = 40,
+    )]
+    tls_cert_file: Option<String>,
+
+    #[structopt(
+        name = "ldap.tls-key-file",
+        long = "ldap.tls-key-file",
+        env = "LDAP_TLS_KEY_FILE",
+        hide_env_values = true,
+        value_name = "file",
+        parse(try_from_str = parse::file),
+        requires = "ldap.tls-cert-file",
+        help = "Path to the client certificate’s private key in PKCS#8 PEM format",
+        display_order = 40,
+    )]
+    tls_key_file: Option<String>,
+
+    #[structopt(
+        name = "ldap.tls-insecure-skip-verify",
+        long = "ldap.tls-insecure-skip-verify",
+        env = "LDAP_TLS_INSECURE_SKIP_VERIFY",
+        hide_env_values = true,
+        value_name = "bool",
+        parse(try_from_str),
+        default_value = "false",
+        help = "Don’t verify the LDAP server’s certificate and hostname (for testing purposes \
+                only)",
+        display_order = 40
+    )]
+    tls_insecure_skip_verify: bool,
 
     #[structopt(
         name = "ldap.bind-dn",
@@ -52,10 +306,11 @@ pub struct Opts {
         env = "LDAP_BIND_DN",
         hide_env_values = true,
         value_name = "string",
-        help = "LDAP DN to bind to",
+        requires = "ldap.bind-pw",
+        help = "LDAP DN to bind to (optional when --ldap.user-bind-template is set)",
         display_order = 41
     )]
-    bind_dn: String,
+    bind_dn: Option<String>,
 
     #[structopt(
         name = "ldap.bind-pw",
@@ -63,10 +318,25 @@ pub struct Opts {
         env = "LDAP_BIND_PW",
         hide_env_values = true,
         value_name = "string",
+        requires = "ldap.bind-dn",
         help = "LDAP bind DN password",
         display_order = 42
     )]
-    bind_pw: String,
+    bind_pw: Option<String>,
+
+    #[structopt(
+        name = "ldap.user-bind-template",
+        long = "ldap.user-bind-template",
+        env = "LDAP_USER_BIND_TEMPLATE",
+        hide_env_values = true,
+        value_name = "string",
+        help = "Name users bind with directly instead of being searched for with the bind DN \
+                first, attributes and groups are then read with the user’s own connection (the \
+                special string `{login}` will be replaced by the user’s provided login, example: \
+                uid={login},ou=people,dc=example,dc=org or {login}@corp.example)",
+        display_order = 42
+    )]
+    user_bind_template: Option<BindTemplate>,
 
     #[structopt(
         name = "ldap.users-dn",
@@ -85,12 +355,26 @@ pub struct Opts {
         env = "LDAP_USERS_FILTER",
         hide_env_values = true,
         value_name = "string",
-        default_value = "(&(objectClass=inetOrgPerson)(|(uid={login})(mail={login})))",
         help = "Search filter for users (the special string `{login}` will be replaced by the \
-                user’s provided login)",
+                user’s provided login, escaped as per RFC 4515) [default: depends on \
+                --ldap.profile, (&(objectClass=inetOrgPerson)(|(uid={login})(mail={login}))) \
+                for the default profile]",
+        display_order = 44
+    )]
+    users_filter: Option<FilterTemplate>,
+
+    #[structopt(
+        name = "ldap.attrs-cardinality",
+        long = "ldap.attrs-cardinality",
+        env = "LDAP_ATTRS_CARDINALITY",
+        hide_env_values = true,
+        value_name = "map",
+        help = "A list of comma separated <LDAP attribute name>:<cardinality> where cardinality \
+                is `single` (values joined with commas, the default), `first` (first value \
+                only) or `array` (JSON array of values)",
         display_order = 44
     )]
-    users_filter: String,
+    attrs_cardinality: Option<Cardinalities>,
 
     #[structopt(
         name = "ldap.groups-dn",
@@ -98,7 +382,8 @@ pub struct Opts {
         env = "LDAP_GROUPS_DN",
         hide_env_values = true,
         value_name = "string",
-        help = "Base DN to search for groups",
+        help = "Base DN to search for groups [default: none, groups not being searched for, or \
+                the user’s domain with active-directory nested groups]",
         display_order = 45
     )]
     groups_dn: Option<String>,
@@ -109,132 +394,983 @@ pub struct Opts {
         env = "LDAP_GROUPS_FILTER",
         hide_env_values = true,
         value_name = "string",
-        default_value = "(&(objectClass=groupOfNames)(member={user_dn}))",
-        help = "Search filter for groups (the special string `{user_dn}` will be replaced by the \
-                user’s DN)",
+        help = "Search filter for groups (the special strings `{user_dn}` and `{attr:<name>}` \
+                will be replaced by the user’s DN and the value of its <name> attribute, escaped \
+                as per RFC 4515) [default: depends on --ldap.profile, \
+                (&(objectClass=groupOfNames)(member={user_dn})) for the default profile]",
         display_order = 46
     )]
-    groups_filter: String,
+    groups_filter: Option<FilterTemplate>,
+
+    #[structopt(
+        name = "ldap.groups-source",
+        long = "ldap.groups-source",
+        env = "LDAP_GROUPS_SOURCE",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["search", "member-of"],
+        default_value = "search",
+        help = "Where to find the user’s groups (`search` searches for groups using the groups \
+                filter, `member-of` reads the user’s memberOf attribute)",
+        display_order = 46
+    )]
+    groups_source: Source,
+
+    #[structopt(
+        name = "ldap.groups-nested",
+        long = "ldap.groups-nested",
+        env = "LDAP_GROUPS_NESTED",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["none", "recursive", "active-directory"],
+        help = "How to resolve nested groups (`none` only returns groups the user is a direct \
+                member of, `recursive` also searches for groups of groups using the groups \
+                filter, `active-directory` uses the LDAP_MATCHING_RULE_IN_CHAIN rule and ignores \
+                the groups filter) [default: none, active-directory with the active-directory \
+                LDAP profile]",
+        display_order = 46
+    )]
+    groups_nested: Option<Nested>,
+
+    #[structopt(
+        name = "ldap.groups-nested-max-depth",
+        long = "ldap.groups-nested-max-depth",
+        env = "LDAP_GROUPS_NESTED_MAX_DEPTH",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "10",
+        help = "Maximum number of levels of groups to search for in `recursive` mode",
+        display_order = 46
+    )]
+    groups_nested_max_depth: usize,
+
+    #[structopt(
+        name = "ldap.groups-attribute",
+        long = "ldap.groups-attribute",
+        env = "LDAP_GROUPS_ATTRIBUTE",
+        hide_env_values = true,
+        value_name = "string",
+        default_value = "cn",
+        help = "Group attribute used as the group name in the groups claim (the special value \
+                `dn` uses the group’s DN)",
+        display_order = 46
+    )]
+    groups_attribute: String,
+
+    #[structopt(
+        name = "ldap.groups-include",
+        long = "ldap.groups-include",
+        env = "LDAP_GROUPS_INCLUDE",
+        hide_env_values = true,
+        value_name = "regex",
+        help = "Regular expression group names must match to be included in the groups claim \
+                (example: ^app-)",
+        display_order = 46
+    )]
+    groups_include: Option<Regex>,
+
+    #[structopt(
+        name = "ldap.groups-rename",
+        long = "ldap.groups-rename",
+        env = "LDAP_GROUPS_RENAME",
+        hide_env_values = true,
+        value_name = "regex",
+        help = "Regular expression replaced in group names by --ldap.groups-rename-replacement, \
+                applied after --ldap.groups-include (example: ^app-)",
+        display_order = 46
+    )]
+    groups_rename: Option<Regex>,
+
+    #[structopt(
+        name = "ldap.groups-rename-replacement",
+        long = "ldap.groups-rename-replacement",
+        env = "LDAP_GROUPS_RENAME_REPLACEMENT",
+        hide_env_values = true,
+        value_name = "string",
+        default_value = "",
+        help = "Replacement for --ldap.groups-rename, capture groups can be referenced with \
+                `$1`, `$name`…",
+        display_order = 46
+    )]
+    groups_rename_replacement: String,
+
+    #[structopt(
+        name = "ldap.pool-max-size",
+        long = "ldap.pool-max-size",
+        env = "LDAP_POOL_MAX_SIZE",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "10",
+        help = "Maximum number of connections bound as the bind DN to keep open",
+        display_order = 47
+    )]
+    pool_max_size: usize,
+
+    #[structopt(
+        name = "ldap.pool-checkout-timeout",
+        long = "ldap.pool-checkout-timeout",
+        env = "LDAP_POOL_CHECKOUT_TIMEOUT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "5",
+        help = "Time in seconds to wait for a connection when all of them are in use",
+        display_order = 48
+    )]
+    pool_checkout_timeout: u64,
+
+    #[structopt(
+        name = "ldap.pool-idle-timeout",
+        long = "ldap.pool-idle-timeout",
+        env = "LDAP_POOL_IDLE_TIMEOUT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "300",
+        help = "Time in seconds after which an unused connection is closed",
+        display_order = 49
+    )]
+    pool_idle_timeout: u64,
+
+    #[structopt(
+        name = "ldap.pool-max-lifetime",
+        long = "ldap.pool-max-lifetime",
+        env = "LDAP_POOL_MAX_LIFETIME",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "1800",
+        help = "Time in seconds after which a connection is no longer reused, it is closed when \
+                returned to the pool or on the next checkout",
+        display_order = 50
+    )]
+    pool_max_lifetime: u64,
+
+    #[structopt(
+        name = "ldap.pool-health-check-interval",
+        long = "ldap.pool-health-check-interval",
+        env = "LDAP_POOL_HEALTH_CHECK_INTERVAL",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "30",
+        help = "Time in seconds after which an unused connection is checked before being reused",
+        display_order = 51
+    )]
+    pool_health_check_interval: u64,
+}
+
+impl Opts {
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+}
+
+// A user whose credentials have been checked.
+pub struct User {
+    pub attrs: HashMap<String, Value>,
+    // Set when the server warns the password is about to expire.
+    pub password_warning: Option<PasswordWarning>,
 }
 
 pub struct LDAP {
-    url: Url,
-    bind_dn: String,
-    bind_pw: String,
+    servers: Servers,
+    settings: LdapConnSettings,
+    timeout: Duration,
+    bind_dn: Option<String>,
+    bind_pw: Option<String>,
+    user_bind_template: Option<BindTemplate>,
+    pool: Pool,
     users_dn: String,
-    users_filter: String,
+    users_filter: FilterTemplate,
+    attrs_cardinality: Cardinalities,
     groups_dn: Option<String>,
-    groups_filter: String,
+    groups_filter: FilterTemplate,
+    groups_source: Source,
+    groups_nested: Nested,
+    groups_nested_max_depth: usize,
+    groups_attribute: String,
+    groups_mapping: Mapping,
+    // Attributes always base64 encoded, even when their value happens to be
+    // valid UTF-8.
+    binary_attrs: Vec<String>,
 }
 
 impl LDAP {
-    pub fn new(opts: Opts) -> LDAP {
-        LDAP {
-            url: opts.url,
+    pub fn new(opts: Opts) -> Result<LDAP, Error> {
+        let users_filter = match opts.users_filter {
+            Some(filter) => filter,
+            None => opts
+                .profile
+                .users_filter()
+                .parse()
+                .map_err(Error::InvalidFilter)?,
+        };
+        let groups_filter = match opts.groups_filter {
+            Some(filter) => filter,
+            None => opts
+                .profile
+                .groups_filter()
+                .parse()
+                .map_err(Error::InvalidFilter)?,
+        };
+        let groups_nested = opts
+            .groups_nested
+            .unwrap_or_else(|| opts.profile.groups_nested());
+
+        users_filter.check_placeholders(&["login"])?;
+        groups_filter.check_placeholders(&["user_dn", "attr:*"])?;
+
+        // Groups of groups are searched for with the group’s DN, they don’t
+        // have the user’s attributes.
+        if groups_nested == Nested::Recursive
+            && groups_filter.placeholders().any(|p| p.starts_with("attr:"))
+        {
+            return Err(Error::InvalidFilter(format!(
+                "`{{attr:<name>}}` placeholders can’t be used with recursive nested groups in \
+                 filter `{}`",
+                groups_filter
+            )));
+        }
+
+        if opts.groups_source == Source::MemberOf && groups_nested != Nested::None {
+            return Err(Error::NestedMemberOf);
+        }
+
+        let groups_filter = match groups_nested {
+            Nested::ActiveDirectory => groups::IN_CHAIN_FILTER
+                .parse()
+                .map_err(Error::InvalidFilter)?,
+            _ => groups_filter,
+        };
+
+        if opts.user_bind_template.is_none() && (opts.bind_dn.is_none() || opts.bind_pw.is_none()) {
+            return Err(Error::MissingBindDn);
+        }
+
+        if opts.starttls && opts.urls.iter().any(|url| url.scheme() == "ldaps") {
+            return Err(Error::StartTlsOverLdaps);
+        }
+
+        if opts.tls_insecure_skip_verify {
+            warn!("LDAP server certificate verification is disabled");
+        }
+
+        let timeout = Duration::from_secs(opts.timeout);
+
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(opts.starttls)
+            .set_connector(tls::connector(
+                opts.tls_ca_file.as_deref(),
+                opts.tls_cert_file.as_deref(),
+                opts.tls_key_file.as_deref(),
+                opts.tls_insecure_skip_verify,
+            )?);
+
+        if opts.pool_max_size == 0 {
+            return Err(Error::InvalidPoolSize);
+        }
+
+        let pool = Pool::new(PoolSettings {
+            max_size: opts.pool_max_size,
+            checkout_timeout: Duration::from_secs(opts.pool_checkout_timeout),
+            idle_timeout: Duration::from_secs(opts.pool_idle_timeout),
+            max_lifetime: Duration::from_secs(opts.pool_max_lifetime),
+            health_check_interval: Duration::from_secs(opts.pool_health_check_interval),
+            timeout,
+        });
+
+        let servers = Servers::new(
+            opts.urls,
+            opts.url_strategy,
+            opts.circuit_breaker_threshold,
+            Duration::from_secs(opts.circuit_breaker_cooldown),
+        );
+
+        Ok(LDAP {
+            servers,
+            settings,
+            timeout,
             bind_dn: opts.bind_dn,
             bind_pw: opts.bind_pw,
+            user_bind_template: opts.user_bind_template,
+            pool,
             users_dn: opts.users_dn,
-            users_filter: opts.users_filter,
+            users_filter,
+            attrs_cardinality: opts.attrs_cardinality.unwrap_or_default(),
             groups_dn: opts.groups_dn,
-            groups_filter: opts.groups_filter,
+            groups_filter,
+            groups_source: opts.groups_source,
+            groups_nested,
+            groups_nested_max_depth: opts.groups_nested_max_depth,
+            groups_attribute: opts.groups_attribute,
+            groups_mapping: Mapping {
+                include: opts.groups_include,
+                rename: opts.groups_rename,
+                rename_replacement: opts.groups_rename_replacement,
+            },
+            binary_attrs: vec![],
+        })
+    }
+
+    pub fn set_binary_attrs(&mut self, attrs: Vec<String>) {
+        self.binary_attrs = attrs;
+    }
+
+    fn is_binary_attr(&self, attr: &str) -> bool {
+        self.binary_attrs
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(attr))
+    }
+
+    // Check the user’s credentials and return their attributes, including
+    // their groups.
+    pub fn login(&self, login: &str, password: &str, attrs: Vec<String>) -> Result<User, Error> {
+        // An empty password would be an unauthenticated bind, which succeeds.
+        if password.is_empty() {
+            return Err(Error::InvalidCredentials);
+        }
+
+        match &self.user_bind_template {
+            Some(template) => {
+                let dn = template.render(login);
+                let bind = self.authenticate(dn.as_str(), password)?;
+                if bind.policy.must_change() {
+                    return Err(Error::PasswordMustChange);
+                }
+
+                let password_warning = bind.policy.warning;
+                let mut conn = Conn::User(bind.conn, bind.server);
+
+                // The users filter may match other entries than the one the
+                // user bound as, whose attributes must not be used.
+                let bound_dn = match template.is_dn() {
+                    true => Some(dn),
+                    false => self.who_am_i(&mut conn)?,
+                };
+                let mut entries = self.search_user(&mut conn, login, attrs)?;
+                let entry = match bound_dn {
+                    Some(dn) => entries
+                        .into_iter()
+                        .find(|entry| entry.dn.eq_ignore_ascii_case(dn.as_str())),
+                    // The server doesn’t tell which entry the user bound
+                    // as, which is then only known if there’s a single one.
+                    None if entries.len() == 1 => entries.pop(),
+                    None => None,
+                };
+                let entry = entry.ok_or(Error::InvalidCredentials)?;
+
+                Ok(User {
+                    attrs: self.user_attrs(&mut conn, entry)?,
+                    password_warning,
+                })
+            }
+            None => {
+                let mut conn = self.service_conn()?;
+
+                let entry = self.find_user(&mut conn, login, attrs)?;
+
+                let bind = self.authenticate(entry.dn.as_str(), password)?;
+                if bind.policy.must_change() {
+                    return Err(Error::PasswordMustChange);
+                }
+
+                Ok(User {
+                    attrs: self.user_attrs(&mut conn, entry)?,
+                    password_warning: bind.policy.warning,
+                })
+            }
         }
     }
 
+    // Change the user’s password with the Password Modify extended operation
+    // (RFC 3062), falling back to replacing userPassword on servers that
+    // don’t support it. The operation is made while bound as the user, so
+    // the server’s own password policy applies.
+    pub fn change_password(
+        &self,
+        login: &str,
+        password: &str,
+        new_password: &str,
+    ) -> Result<(), Error> {
+        if password.is_empty() {
+            return Err(Error::InvalidCredentials);
+        }
+
+        let dn = match &self.user_bind_template {
+            Some(template) => template.render(login),
+            None => {
+                let mut conn = self.service_conn()?;
+                self.find_user(&mut conn, login, vec!["1.1".to_string()])?
+                    .dn
+            }
+        };
+
+        // A user whose password must be changed is still able to bind.
+        let bind = self.authenticate(dn.as_str(), password)?;
+        let mut conn = Conn::User(bind.conn, bind.server);
+
+        self.modify_password(&mut conn, dn.as_str(), Some(password), new_password)
+    }
+
+    // Check that an LDAP server is reachable, binding as the bind DN when
+    // there is one. A new connection is opened rather than checking a pooled
+    // one out so the check reflects the servers’ current state.
+    pub fn check(&self) -> Result<(), Error> {
+        match (&self.bind_dn, &self.bind_pw) {
+            (Some(bind_dn), Some(bind_pw)) => {
+                let mut bind = self.authenticate(bind_dn.as_str(), bind_pw.as_str())?;
+                let _ = bind.conn.unbind();
+            }
+            _ => self.servers.try_each(|url| {
+                let mut conn = LdapConn::with_settings(self.settings.clone(), url.as_str())?;
+                let _ = conn.unbind();
+                Ok(())
+            })?,
+        };
+
+        Ok(())
+    }
+
+    // Look a user up with the service account, without authenticating them.
+    // Their groups are not resolved, so the lookup takes about as long
+    // whether the user exists or not.
     pub fn get_user_attrs(
         &self,
         login: &str,
         attrs: Vec<String>,
     ) -> Result<HashMap<String, Value>, Error> {
-        let filter: String = self.users_filter.replace("{login}", login);
+        let mut conn = self.service_conn()?;
 
-        let entries = self.search(self.users_dn.as_str(), filter.as_str(), attrs)?;
+        let entry = self.find_user(&mut conn, login, attrs)?;
 
-        if let Some(entry) = entries.first() {
-            let entry = SearchEntry::construct(entry.clone());
+        Ok(self.entry_attrs(entry))
+    }
 
-            let mut h: HashMap<String, Value> = HashMap::new();
-            h.insert("dn".to_string(), json!(entry.dn));
+    // Set the user’s password with the service account, e.g. once they proved
+    // they own the account by other means than their current password.
+    pub fn set_password(&self, login: &str, new_password: &str) -> Result<(), Error> {
+        let mut conn = self.service_conn()?;
 
-            for (attr, values) in entry.attrs {
-                let value = match values.len() {
-                    1 => values[0].clone(),
-                    _ => values.join(","),
-                };
-                h.insert(attr, json!(value));
+        let dn = self
+            .find_user(&mut conn, login, vec!["1.1".to_string()])?
+            .dn;
+
+        self.modify_password(&mut conn, dn.as_str(), None, new_password)
+    }
+
+    fn modify_password(
+        &self,
+        conn: &mut Conn,
+        dn: &str,
+        password: Option<&str>,
+        new_password: &str,
+    ) -> Result<(), Error> {
+        let _timer = metrics::ldap_timer("password_modify");
+
+        let r = match conn
+            .with_controls(ppolicy::request())
+            .with_timeout(self.timeout)
+            .extended(PasswordModify {
+                user_id: Some(dn),
+                old_pass: password,
+                new_pass: Some(new_password),
+            }) {
+            Ok(ExopResult(_, r)) => r,
+            Err(e) => {
+                conn.mark_broken();
+                return Err(Error::LdapError(e));
             }
+        };
 
-            let groups = self.get_user_groups(entry.dn.as_str())?;
-            h.insert("groups".to_string(), json!(groups));
+        // LDAP_PROTOCOL_ERROR is returned for unsupported operations.
+        let r = match r.rc {
+            2 => {
+                debug!(
+                    "Password Modify extended operation not supported by {}",
+                    conn.server()
+                );
 
-            Ok(h)
-        } else {
-            Err(Error::UserNotFound(login.to_string()))
+                match conn
+                    .with_controls(ppolicy::request())
+                    .with_timeout(self.timeout)
+                    .modify(
+                        dn,
+                        vec![Mod::Replace(
+                            "userPassword",
+                            HashSet::from_iter(vec![new_password]),
+                        )],
+                    ) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        conn.mark_broken();
+                        return Err(Error::LdapError(e));
+                    }
+                }
+            }
+            _ => r,
+        };
+
+        Self::check_password_change(r)?;
+
+        info!("Changed password of `{}` on {}", dn, conn.server());
+
+        Ok(())
+    }
+
+    fn check_password_change(r: LdapResult) -> Result<(), Error> {
+        if let Some(reason) = ppolicy::response(&r.ctrls).and_then(|p| p.rejection()) {
+            return Err(Error::PasswordRejected(reason.to_string()));
+        }
+
+        // LDAP_CONSTRAINT_VIOLATION
+        if r.rc == 19 {
+            return Err(Error::PasswordRejected(match r.text.is_empty() {
+                true => "password rejected by the server".to_string(),
+                false => r.text,
+            }));
+        }
+
+        r.success()?;
+
+        Ok(())
+    }
+
+    fn find_user(
+        &self,
+        conn: &mut Conn,
+        login: &str,
+        attrs: Vec<String>,
+    ) -> Result<SearchEntry, Error> {
+        match self.search_user(conn, login, attrs)?.into_iter().next() {
+            Some(entry) => Ok(entry),
+            None => Err(Error::UserNotFound(login.to_string())),
         }
     }
 
-    pub fn validate_credentials(&self, dn: &str, password: &str) -> Result<bool, Error> {
-        match self.authenticate(dn, password) {
-            Ok(_) => Ok(true),
+    fn search_user(
+        &self,
+        conn: &mut Conn,
+        login: &str,
+        attrs: Vec<String>,
+    ) -> Result<Vec<SearchEntry>, Error> {
+        let filter: String = self.users_filter.render(|_| Some(login.to_string()))?;
+
+        let mut attrs = attrs;
+        attrs.extend(self.groups_user_attrs());
+
+        let entries = self.search(
+            conn,
+            self.users_dn.as_str(),
+            Scope::Subtree,
+            filter.as_str(),
+            attrs,
+        )?;
+
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    // The DN the connection is bound as, from the Who Am I extended operation
+    // (RFC 4532). Servers may answer with another form of authorization
+    // identity (e.g. `u:CORP\jdoe` for Active Directory) or not support the
+    // operation, in which case the DN isn’t known.
+    fn who_am_i(&self, conn: &mut Conn) -> Result<Option<String>, Error> {
+        let r = match conn.with_timeout(self.timeout).extended(WhoAmI) {
+            Ok(r) => r,
             Err(e) => {
-                if let Error::InvalidCredentials = e {
-                    Ok(false)
-                } else {
-                    Err(e)
-                }
+                conn.mark_broken();
+                return Err(Error::LdapError(e));
             }
+        };
+
+        let (exop, _) = match r.success() {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(
+                    "Who Am I extended operation failed on {}: {}",
+                    conn.server(),
+                    e
+                );
+                return Ok(None);
+            }
+        };
+
+        let authz_id = exop.parse::<WhoAmIResp>().authzid;
+
+        Ok(authz_id.strip_prefix("dn:").map(|dn| dn.to_string()))
+    }
+
+    fn user_attrs(
+        &self,
+        conn: &mut Conn,
+        entry: SearchEntry,
+    ) -> Result<HashMap<String, Value>, Error> {
+        let groups = self.get_user_groups(conn, &entry)?;
+
+        let mut h = self.entry_attrs(entry);
+        h.insert("groups".to_string(), json!(groups));
+
+        Ok(h)
+    }
+
+    fn entry_attrs(&self, entry: SearchEntry) -> HashMap<String, Value> {
+        let mut h: HashMap<String, Value> = HashMap::new();
+        h.insert("dn".to_string(), json!(entry.dn));
+
+        for (attr, values) in entry.attrs {
+            let values = match self.is_binary_attr(&attr) {
+                true => values.iter().map(base64::encode).collect(),
+                false => values,
+            };
+            let value = self.attrs_cardinality.to_value(&attr, values);
+            h.insert(attr, value);
         }
+
+        // Binary attributes (e.g. objectGUID) are base64 encoded.
+        for (attr, values) in entry.bin_attrs {
+            let values: Vec<String> = values.iter().map(base64::encode).collect();
+            let value = self.attrs_cardinality.to_value(&attr, values);
+            h.insert(attr, value);
+        }
+
+        h
     }
 
-    fn authenticate(&self, dn: &str, password: &str) -> Result<LdapConn, Error> {
-        let mut conn = LdapConn::new(self.url.as_str())?;
-        let r = conn.simple_bind(dn, password).map_err(Error::LdapError)?;
+    // Check out a connection bound as the bind DN.
+    fn service_conn(&self) -> Result<Conn, Error> {
+        let (bind_dn, bind_pw) = match (&self.bind_dn, &self.bind_pw) {
+            (Some(bind_dn), Some(bind_pw)) => (bind_dn, bind_pw),
+            _ => return Err(Error::MissingBindDn),
+        };
+
+        let conn = self.pool.get(|| {
+            self.authenticate(bind_dn.as_str(), bind_pw.as_str())
+                .map(|bind| (bind.conn, bind.server))
+        })?;
+
+        Ok(Conn::Pooled(conn))
+    }
+
+    // Open a connection bound as `dn`. The password policy control is sent
+    // along so the server can explain why a bind failed, or warn about the
+    // password expiring.
+    fn authenticate(&self, dn: &str, password: &str) -> Result<Bind, Error> {
+        self.servers.try_each(|url| {
+            let _timer = metrics::ldap_timer("bind");
+
+            let mut conn = LdapConn::with_settings(self.settings.clone(), url.as_str())?;
+            let r = conn
+                .with_controls(ppolicy::request())
+                .with_timeout(self.timeout)
+                .simple_bind(dn, password)?;
+
+            let policy = ppolicy::response(&r.ctrls).unwrap_or_default();
+
+            // LDAP_INVALID_CREDENTIALS
+            if r.rc == 49 {
+                return Err(policy
+                    .to_error()
+                    .or_else(|| ad::bind_error(dn, r.text.as_str()))
+                    .unwrap_or(Error::InvalidCredentials));
+            }
+
+            r.success()?;
+
+            Ok(Bind {
+                conn,
+                server: url.clone(),
+                policy,
+            })
+        })
+    }
 
-        // LDAP_INVALID_CREDENTIALS
-        if r.rc == 49 {
-            Err(Error::InvalidCredentials)
-        } else {
-            Ok(conn)
+    // User attributes needed to find the user’s groups.
+    fn groups_user_attrs(&self) -> Vec<String> {
+        match self.groups_source {
+            Source::MemberOf => vec!["memberOf".to_string()],
+            Source::Search => self
+                .groups_filter
+                .placeholders()
+                .filter_map(|p| p.strip_prefix("attr:"))
+                .map(|attr| attr.to_string())
+                .collect(),
         }
     }
 
-    fn get_user_groups(&self, user_dn: &str) -> Result<Vec<String>, Error> {
-        let base_dn = match self.groups_dn.clone() {
-            Some(dn) => dn,
-            None => {
+    fn get_user_groups(&self, conn: &mut Conn, user: &SearchEntry) -> Result<Vec<String>, Error> {
+        let names = match self.groups_source {
+            Source::MemberOf => self.get_member_of_groups(conn, user)?,
+            Source::Search => self.search_user_groups(conn, user)?,
+        };
+
+        let mut groups: Vec<String> = vec![];
+
+        for name in names {
+            let name = match self.groups_mapping.apply(name.as_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if !groups.contains(&name) {
+                groups.push(name);
+            }
+        }
+
+        Ok(groups)
+    }
+
+    fn search_user_groups(
+        &self,
+        conn: &mut Conn,
+        user: &SearchEntry,
+    ) -> Result<Vec<String>, Error> {
+        let base_dn = match (self.groups_dn.clone(), self.groups_nested) {
+            (Some(dn), _) => dn,
+            // Active Directory resolves nested groups across the domain, which
+            // is searched as a whole when no base DN is set.
+            (None, Nested::ActiveDirectory) => match groups::domain_dn(user.dn.as_str()) {
+                Some(dn) => dn,
+                None => {
+                    debug!(
+                        "Skipping searching for groups as `{}` has no domain",
+                        user.dn
+                    );
+                    return Ok(vec![]);
+                }
+            },
+            (None, _) => {
                 debug!("Skipping searching for groups as groups search DN is not set");
                 return Ok(vec![]);
             }
         };
 
-        let filter: String = self.groups_filter.replace("{user_dn}", user_dn);
+        for attr in self.groups_user_attrs() {
+            if attrs::values(user, attr.as_str()).map_or(true, |v| v.is_empty()) {
+                debug!(
+                    "Skipping searching for groups as `{}` has no `{}` attribute",
+                    user.dn, attr
+                );
+                return Ok(vec![]);
+            }
+        }
 
-        let mut groups: Vec<String> = vec![];
+        let entries = match self.groups_nested {
+            Nested::Recursive => {
+                self.search_groups_recursive(conn, base_dn.as_str(), user.dn.as_str())?
+            }
+            _ => self.search_groups(conn, base_dn.as_str(), |name| match name {
+                "user_dn" => Some(user.dn.clone()),
+                name => attrs::values(user, name.strip_prefix("attr:")?)?
+                    .first()
+                    .cloned(),
+            })?,
+        };
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| self.group_name(entry))
+            .collect())
+    }
+
+    // Groups are read from the user’s memberOf values, the group entry is
+    // only read when its name can’t be taken from its DN.
+    fn get_member_of_groups(
+        &self,
+        conn: &mut Conn,
+        user: &SearchEntry,
+    ) -> Result<Vec<String>, Error> {
+        let mut names: Vec<String> = vec![];
+
+        for dn in attrs::values(user, "memberOf").into_iter().flatten() {
+            if self.groups_attribute == "dn" {
+                names.push(dn.clone());
+                continue;
+            }
+
+            if let Some(name) = groups::rdn_value(dn, self.groups_attribute.as_str()) {
+                names.push(name);
+                continue;
+            }
+
+            let entries = self.search(
+                conn,
+                dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec![self.groups_attribute.clone()],
+            )?;
+
+            names.extend(
+                entries
+                    .into_iter()
+                    .map(SearchEntry::construct)
+                    .filter_map(|entry| self.group_name(entry)),
+            );
+        }
+
+        Ok(names)
+    }
+
+    fn group_name(&self, mut entry: SearchEntry) -> Option<String> {
+        if self.groups_attribute == "dn" {
+            return Some(entry.dn);
+        }
+
+        entry
+            .attrs
+            .drain()
+            .find(|(attr, _)| attr.eq_ignore_ascii_case(self.groups_attribute.as_str()))
+            .and_then(|(_, mut values)| match values.is_empty() {
+                true => None,
+                false => Some(values.remove(0)),
+            })
+    }
+
+    // Search for groups using the groups filter, placeholders being replaced
+    // by `lookup`.
+    fn search_groups<F>(
+        &self,
+        conn: &mut Conn,
+        base_dn: &str,
+        lookup: F,
+    ) -> Result<Vec<SearchEntry>, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let filter: String = self.groups_filter.render(lookup)?;
+
+        // `1.1` means no attribute at all.
+        let attrs = match self.groups_attribute.as_str() {
+            "dn" => vec!["1.1".to_string()],
+            attr => vec![attr.to_string()],
+        };
+
+        Ok(self
+            .search(conn, base_dn, Scope::Subtree, filter.as_str(), attrs)?
+            .into_iter()
+            .map(SearchEntry::construct)
+            .collect())
+    }
+
+    // Search for groups `user_dn` is a member of, then for groups those groups
+    // are members of, and so on until no new group is found or the maximum
+    // depth is reached.
+    fn search_groups_recursive(
+        &self,
+        conn: &mut Conn,
+        base_dn: &str,
+        user_dn: &str,
+    ) -> Result<Vec<SearchEntry>, Error> {
+        let mut groups: Vec<SearchEntry> = vec![];
+        // DNs are case insensitive.
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut members: Vec<String> = vec![user_dn.to_string()];
 
-        for entry in self.search(base_dn.as_str(), filter.as_str(), vec!["cn".to_string()])? {
-            let entry = SearchEntry::construct(entry.clone());
+        for _ in 0..self.groups_nested_max_depth {
+            let mut parents: Vec<String> = vec![];
 
-            for (attr, values) in entry.attrs {
-                if attr == "cn" {
-                    groups.push(values[0].clone());
+            for member in members {
+                for entry in self.search_groups(conn, base_dn, |_| Some(member.clone()))? {
+                    if seen.insert(entry.dn.to_lowercase()) {
+                        parents.push(entry.dn.clone());
+                        groups.push(entry);
+                    } else {
+                        debug!("Skipping group `{}` already found", entry.dn);
+                    }
                 }
             }
+
+            members = parents;
+
+            if members.is_empty() {
+                return Ok(groups);
+            }
         }
 
+        warn!(
+            "Stopped searching for groups of `{}` after {} levels of nesting",
+            user_dn, self.groups_nested_max_depth
+        );
+
         Ok(groups)
     }
 
     fn search(
         &self,
+        conn: &mut Conn,
         base_dn: &str,
+        scope: Scope,
         filter: &str,
         attrs: Vec<String>,
     ) -> Result<Vec<ResultEntry>, Error> {
-        let mut conn = self.authenticate(self.bind_dn.as_str(), self.bind_pw.as_str())?;
+        debug!(
+            "Searching `{}` in `{}` on {}",
+            filter,
+            base_dn,
+            conn.server()
+        );
+
+        let _timer = metrics::ldap_timer("search");
+
+        let r = match conn
+            .with_timeout(self.timeout)
+            .search(base_dn, scope, filter, attrs)
+        {
+            Ok(r) => r,
+            Err(e) => {
+                conn.mark_broken();
+                return Err(Error::LdapError(e));
+            }
+        };
 
-        let (entries, _) = conn
-            .search(base_dn, Scope::Subtree, filter, attrs)?
-            .success()?;
+        let (entries, _) = r.success()?;
 
         Ok(entries)
     }
 }
+
+// A connection opened by `LDAP::authenticate`.
+struct Bind {
+    conn: LdapConn,
+    server: Url,
+    policy: ppolicy::Response,
+}
+
+// Connection searches are made with, either a pooled connection bound as the
+// bind DN or the user’s own connection in direct bind mode.
+enum Conn<'a> {
+    Pooled(PooledConn<'a>),
+    User(LdapConn, Url),
+}
+
+impl Conn<'_> {
+    fn server(&self) -> &Url {
+        match self {
+            Conn::Pooled(conn) => conn.server(),
+            Conn::User(_, server) => server,
+        }
+    }
+
+    fn mark_broken(&mut self) {
+        if let Conn::Pooled(conn) = self {
+            conn.mark_broken();
+        }
+    }
+}
+
+impl Deref for Conn<'_> {
+    type Target = LdapConn;
+
+    fn deref(&self) -> &LdapConn {
+        match self {
+            Conn::Pooled(conn) => conn,
+            Conn::User(conn, _) => conn,
+        }
+    }
+}
+
+impl DerefMut for Conn<'_> {
+    fn deref_mut(&mut self) -> &mut LdapConn {
+        match self {
+            Conn::Pooled(conn) => conn,
+            Conn::User(conn, _) => conn,
+        }
+    }
+}
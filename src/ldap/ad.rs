@@ -0,0 +1,73 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Error;
+
+// Active Directory explains why a bind failed with LDAP_INVALID_CREDENTIALS
+// in the diagnostic message, e.g.:
+//
+//   80090308: LdapErr: DSID-0C09042A, comment: AcceptSecurityContext error, data 52e, v3839
+//
+// Returns `None` for messages without a known sub-code.
+pub fn bind_error(dn: &str, message: &str) -> Option<Error> {
+    let code = message
+        .split(", ")
+        .find_map(|part| part.strip_prefix("data "))?;
+
+    match code.to_lowercase().as_str() {
+        "525" => Some(Error::UserNotFound(dn.to_string())),
+        "52e" => Some(Error::InvalidCredentials),
+        // Logon hours and workstation restrictions
+        "530" | "531" => Some(Error::LoginRestricted),
+        "532" => Some(Error::PasswordExpired),
+        "533" => Some(Error::AccountDisabled),
+        "701" => Some(Error::AccountExpired),
+        "773" => Some(Error::PasswordMustReset),
+        "775" => Some(Error::AccountLocked),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(code: &str) -> String {
+        format!(
+            "80090308: LdapErr: DSID-0C09042A, comment: AcceptSecurityContext error, data {}, v3839",
+            code
+        )
+    }
+
+    #[test]
+    fn parses_sub_codes() {
+        let dn = "CN=jdoe,DC=corp,DC=example";
+
+        assert!(matches!(
+            bind_error(dn, &message("52e")),
+            Some(Error::InvalidCredentials)
+        ));
+        assert!(matches!(
+            bind_error(dn, &message("773")),
+            Some(Error::PasswordMustReset)
+        ));
+        assert!(matches!(
+            bind_error(dn, &message("775")),
+            Some(Error::AccountLocked)
+        ));
+        assert!(bind_error(dn, &message("fff")).is_none());
+        assert!(bind_error(dn, "Invalid credentials").is_none());
+    }
+}
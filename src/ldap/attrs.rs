@@ -0,0 +1,97 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use ldap3::SearchEntry;
+use serde_json::json;
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::parse;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cardinality {
+    // A single string, multiple values being joined with commas.
+    Single,
+    // The first value only.
+    First,
+    // A JSON array of every value.
+    Array,
+}
+
+impl FromStr for Cardinality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "single" => Ok(Cardinality::Single),
+            "first" => Ok(Cardinality::First),
+            "array" => Ok(Cardinality::Array),
+            _ => Err(format!("unknown attribute cardinality: {}", value)),
+        }
+    }
+}
+
+// Cardinality of each attribute, keyed by lowercased attribute name as LDAP
+// attribute names are case insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Cardinalities(HashMap<String, Cardinality>);
+
+impl Cardinalities {
+    pub fn get(&self, attr: &str) -> Cardinality {
+        self.0
+            .get(&attr.to_lowercase())
+            .copied()
+            .unwrap_or(Cardinality::Single)
+    }
+
+    pub fn to_value(&self, attr: &str, mut values: Vec<String>) -> Value {
+        match self.get(attr) {
+            Cardinality::Single => match values.len() {
+                1 => json!(values.remove(0)),
+                _ => json!(values.join(",")),
+            },
+            Cardinality::First => match values.is_empty() {
+                true => Value::Null,
+                false => json!(values.remove(0)),
+            },
+            Cardinality::Array => json!(values),
+        }
+    }
+}
+
+impl FromStr for Cardinalities {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut h: HashMap<String, Cardinality> = HashMap::new();
+
+        for (attr, cardinality) in parse::comma_separated_key_value(value)? {
+            h.insert(attr.to_lowercase(), Cardinality::from_str(&cardinality)?);
+        }
+
+        Ok(Cardinalities(h))
+    }
+}
+
+// Values of `attr` in `entry`, attribute names being compared case
+// insensitively.
+pub fn values<'a>(entry: &'a SearchEntry, attr: &str) -> Option<&'a Vec<String>> {
+    entry
+        .attrs
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(attr))
+        .map(|(_, values)| values)
+}
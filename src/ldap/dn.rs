@@ -0,0 +1,128 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use ldap3::dn_escape;
+use std::str::FromStr;
+
+const LOGIN_PLACEHOLDER: &str = "{login}";
+
+// The name users bind with in direct bind mode, either a DN
+// (`uid={login},ou=people,dc=example,dc=org`), a user principal name
+// (`{login}@corp.example`) or a down-level logon name
+// (`CORP\{login}`). The login is escaped according to RFC 4514 in DNs only,
+// other names being used as is by the server.
+#[derive(Debug, Clone)]
+pub struct BindTemplate {
+    template: String,
+    is_dn: bool,
+}
+
+impl BindTemplate {
+    pub fn render(&self, login: &str) -> String {
+        match self.is_dn {
+            true => self
+                .template
+                .replace(LOGIN_PLACEHOLDER, dn_escape(login).as_ref()),
+            false => self.template.replace(LOGIN_PLACEHOLDER, login),
+        }
+    }
+
+    pub fn is_dn(&self) -> bool {
+        self.is_dn
+    }
+}
+
+impl FromStr for BindTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if !value.contains(LOGIN_PLACEHOLDER) {
+            return Err(format!(
+                "missing `{}` placeholder in bind template: {}",
+                LOGIN_PLACEHOLDER, value
+            ));
+        }
+
+        Ok(BindTemplate {
+            template: value.to_string(),
+            // Attribute types and values are separated by `=` in DNs, which
+            // can’t appear in the other forms.
+            is_dn: value.contains('='),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, login: &str) -> String {
+        template.parse::<BindTemplate>().unwrap().render(login)
+    }
+
+    #[test]
+    fn escapes_logins_in_dns() {
+        let template = "uid={login},ou=people,dc=example,dc=org";
+
+        assert_eq!(
+            render(template, "jdoe"),
+            "uid=jdoe,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, "jdoe,ou=admins"),
+            "uid=jdoe\\,ou\\=admins,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, "j+doe"),
+            "uid=j\\+doe,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, "j\"doe\\"),
+            "uid=j\\\"doe\\\\,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, "#jdoe"),
+            "uid=\\#jdoe,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, " jdoe "),
+            "uid=\\ jdoe\\ ,ou=people,dc=example,dc=org"
+        );
+        assert_eq!(
+            render(template, "j#doe j"),
+            "uid=j#doe j,ou=people,dc=example,dc=org"
+        );
+    }
+
+    #[test]
+    fn keeps_logins_in_other_names() {
+        assert_eq!(
+            render("{login}@corp.example", "j+doe,x"),
+            "j+doe,x@corp.example"
+        );
+        assert_eq!(
+            render("{login}@corp.example", "#j\"doe "),
+            "#j\"doe @corp.example"
+        );
+        assert_eq!(render("CORP\\{login}", "j\\doe"), "CORP\\j\\doe");
+    }
+
+    #[test]
+    fn requires_placeholder() {
+        assert!("uid=jdoe,dc=example,dc=org"
+            .parse::<BindTemplate>()
+            .is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use ldap3::{ldap_escape, parse_filter};
+use std::fmt;
+use std::str::FromStr;
+
+use super::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+// A search filter containing `{name}` placeholders. Templates are parsed and
+// checked when options are parsed, and every value substituted at render time
+// is escaped according to RFC 4515 so user input can’t alter the filter.
+#[derive(Debug, Clone)]
+pub struct FilterTemplate {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl FilterTemplate {
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|s| match s {
+            Segment::Placeholder(name) => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    // Ensure the template only references placeholders from `allowed`. An
+    // allowed name ending with `*` matches any placeholder starting with the
+    // same prefix.
+    pub fn check_placeholders(&self, allowed: &[&str]) -> Result<(), Error> {
+        for name in self.placeholders() {
+            let is_allowed = allowed.iter().any(|a| match a.strip_suffix('*') {
+                Some(prefix) => name.len() > prefix.len() && name.starts_with(prefix),
+                None => *a == name,
+            });
+
+            if !is_allowed {
+                return Err(Error::InvalidFilter(format!(
+                    "unknown placeholder `{{{}}}` in filter `{}` (allowed: {})",
+                    name,
+                    self.template,
+                    allowed
+                        .iter()
+                        .map(|a| format!("`{{{}}}`", a))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Render the filter, escaping every value returned by `lookup`.
+    pub fn render<F>(&self, lookup: F) -> Result<String, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut filter = String::with_capacity(self.template.len());
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => filter.push_str(literal),
+                Segment::Placeholder(name) => match lookup(name) {
+                    Some(value) => filter.push_str(&ldap_escape(value)),
+                    None => {
+                        return Err(Error::InvalidFilter(format!(
+                            "no value for placeholder `{{{}}}` in filter `{}`",
+                            name, self.template
+                        )))
+                    }
+                },
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl FromStr for FilterTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut segments: Vec<Segment> = vec![];
+        let mut rest = value;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or(format!("unterminated placeholder in filter: {}", value))?;
+
+            let name = &rest[start + 1..end];
+            if name.is_empty() || name.contains('{') {
+                return Err(format!("invalid placeholder in filter: {}", value));
+            }
+
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            segments.push(Segment::Placeholder(name.to_string()));
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        let template = FilterTemplate {
+            template: value.to_string(),
+            segments,
+        };
+
+        // Placeholders are replaced by a dummy value so the resulting filter
+        // can be checked by the LDAP filter parser.
+        let sample = template
+            .render(|_| Some("x".to_string()))
+            .map_err(|e| e.to_string())?;
+
+        if parse_filter(&sample).is_err() {
+            return Err(format!("invalid LDAP filter: {}", value));
+        }
+
+        Ok(template)
+    }
+}
+
+impl fmt::Display for FilterTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, value: &str) -> String {
+        template
+            .parse::<FilterTemplate>()
+            .unwrap()
+            .render(|_| Some(value.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(render("(uid={login})", "jdoe"), "(uid=jdoe)");
+        assert_eq!(render("(uid={login})", "*"), "(uid=\\2a)");
+        assert_eq!(
+            render("(uid={login})", "*)(uid=*"),
+            "(uid=\\2a\\29\\28uid=\\2a)"
+        );
+        assert_eq!(render("(cn={login})", "a\\b"), "(cn=a\\5cb)");
+        assert_eq!(render("(cn={login})", "a\0b"), "(cn=a\\00b)");
+    }
+
+    #[test]
+    fn keeps_literals() {
+        assert_eq!(
+            render(
+                "(&(objectClass=person)(|(uid={login})(mail={login})))",
+                "jdoe"
+            ),
+            "(&(objectClass=person)(|(uid=jdoe)(mail=jdoe)))"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!("(uid={login)".parse::<FilterTemplate>().is_err());
+        assert!("(uid={})".parse::<FilterTemplate>().is_err());
+        assert!("(uid={{login})".parse::<FilterTemplate>().is_err());
+        assert!("(uid={login}".parse::<FilterTemplate>().is_err());
+    }
+
+    #[test]
+    fn fails_on_missing_values() {
+        let template: FilterTemplate = "(uid={login})".parse().unwrap();
+        assert!(template.render(|_| None).is_err());
+    }
+
+    #[test]
+    fn checks_placeholders() {
+        let users: &[&str] = &["login"];
+        let groups: &[&str] = &["user_dn", "attr:*"];
+
+        let template: FilterTemplate = "(|(uid={login})(mail={login}))".parse().unwrap();
+        assert!(template.check_placeholders(users).is_ok());
+        assert!(template.check_placeholders(groups).is_err());
+
+        let template: FilterTemplate = "(&(member={user_dn})(o={attr:o}))".parse().unwrap();
+        assert!(template.check_placeholders(groups).is_ok());
+        assert!(template.check_placeholders(users).is_err());
+        assert!(template.check_placeholders(&["user_dn"]).is_err());
+
+        let template: FilterTemplate = "(o={attr:})".parse().unwrap();
+        assert!(template.check_placeholders(groups).is_err());
+
+        let template: FilterTemplate = "(member={dn})".parse().unwrap();
+        assert!(template.check_placeholders(groups).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use regex::Regex;
+use std::str::FromStr;
+
+// Groups filter used in Active Directory mode. LDAP_MATCHING_RULE_IN_CHAIN
+// makes the server walk the whole membership chain.
+pub const IN_CHAIN_FILTER: &str =
+    "(&(objectClass=group)(member:1.2.840.113556.1.4.1941:={user_dn}))";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nested {
+    // Only groups the user is a direct member of.
+    None,
+    // Groups of groups are searched for iteratively.
+    Recursive,
+    // The server resolves nested groups itself.
+    ActiveDirectory,
+}
+
+impl FromStr for Nested {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Nested::None),
+            "recursive" => Ok(Nested::Recursive),
+            "active-directory" => Ok(Nested::ActiveDirectory),
+            _ => Err(format!("unknown nested groups mode: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    // Groups are searched for with the groups filter.
+    Search,
+    // Groups are read from the user’s `memberOf` attribute.
+    MemberOf,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "search" => Ok(Source::Search),
+            "member-of" => Ok(Source::MemberOf),
+            _ => Err(format!("unknown groups source: {}", value)),
+        }
+    }
+}
+
+// Value of the first RDN of `dn` if its attribute is `attr` (e.g. `admins`
+// for `cn=admins,ou=groups,dc=example,dc=org` and `cn`), so group names can
+// be read from `memberOf` values without searching for each group.
+pub fn rdn_value(dn: &str, attr: &str) -> Option<String> {
+    let (rdn_attr, rest) = dn.split_at(dn.find('=')?);
+
+    if !rdn_attr.trim().eq_ignore_ascii_case(attr) {
+        return None;
+    }
+
+    let mut value = String::new();
+    let mut chars = rest[1..].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // Hex escaped values are left to the server.
+            '\\' => match chars.next()? {
+                c if c.is_ascii_hexdigit() => return None,
+                c => value.push(c),
+            },
+            ',' => break,
+            // Multi-valued RDNs are not supported.
+            '+' => return None,
+            c => value.push(c),
+        }
+    }
+
+    Some(value.trim().to_string())
+}
+
+// The domain `dn` belongs to, made of its trailing `dc` RDNs (e.g.
+// `dc=corp,dc=example,dc=org` for `cn=jdoe,ou=people,dc=corp,dc=example,dc=org`),
+// which is the default naming context of Active Directory users.
+pub fn domain_dn(dn: &str) -> Option<String> {
+    let mut rdns: Vec<&str> = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in dn.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                rdns.push(&dn[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    rdns.push(&dn[start..]);
+
+    let domain: Vec<&str> = rdns
+        .iter()
+        .rev()
+        .map(|rdn| rdn.trim())
+        .take_while(|rdn| {
+            rdn.split('=')
+                .next()
+                .map_or(false, |attr| attr.trim().eq_ignore_ascii_case("dc"))
+        })
+        .collect();
+
+    match domain.is_empty() {
+        true => None,
+        false => Some(domain.into_iter().rev().collect::<Vec<&str>>().join(",")),
+    }
+}
+
+// Turns group names into the values of the `groups` claim.
+#[derive(Debug)]
+pub struct Mapping {
+    // Only groups whose name matches are kept.
+    pub include: Option<Regex>,
+    pub rename: Option<Regex>,
+    pub rename_replacement: String,
+}
+
+impl Mapping {
+    pub fn apply(&self, name: &str) -> Option<String> {
+        if let Some(include) = &self.include {
+            if !include.is_match(name) {
+                debug!("Skipping group `{}` not matching `{}`", name, include);
+                return None;
+            }
+        }
+
+        match &self.rename {
+            Some(rename) => Some(
+                rename
+                    .replace(name, self.rename_replacement.as_str())
+                    .to_string(),
+            ),
+            None => Some(name.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_rdn_values() {
+        let dn = "cn=admins,ou=groups,dc=example,dc=org";
+
+        assert_eq!(rdn_value(dn, "cn"), Some("admins".to_string()));
+        assert_eq!(rdn_value(dn, "CN"), Some("admins".to_string()));
+        assert_eq!(rdn_value(dn, "ou"), None);
+        assert_eq!(rdn_value("cn=a\\,b,dc=org", "cn"), Some("a,b".to_string()));
+        assert_eq!(rdn_value("cn=a\\2cb,dc=org", "cn"), None);
+        assert_eq!(rdn_value("cn=a+ou=b,dc=org", "cn"), None);
+    }
+
+    #[test]
+    fn finds_domains() {
+        assert_eq!(
+            domain_dn("CN=John Doe,OU=People,DC=corp,DC=example,DC=org"),
+            Some("DC=corp,DC=example,DC=org".to_string())
+        );
+        assert_eq!(
+            domain_dn("cn=Doe\\, John,dc=example, dc=org"),
+            Some("dc=example,dc=org".to_string())
+        );
+        assert_eq!(domain_dn("cn=jdoe,ou=people,o=example"), None);
+        assert_eq!(domain_dn(""), None);
+    }
+
+    #[test]
+    fn maps_group_names() {
+        let mapping = Mapping {
+            include: Some(Regex::new("^app-").unwrap()),
+            rename: Some(Regex::new("^app-").unwrap()),
+            rename_replacement: "".to_string(),
+        };
+
+        assert_eq!(mapping.apply("app-wiki"), Some("wiki".to_string()));
+        assert_eq!(mapping.apply("staff"), None);
+    }
+}
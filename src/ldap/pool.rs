@@ -0,0 +1,228 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use ldap3::{LdapConn, Scope};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use super::Error;
+use crate::metrics;
+
+pub struct PoolSettings {
+    pub max_size: usize,
+    pub checkout_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_lifetime: Duration,
+    pub health_check_interval: Duration,
+    pub timeout: Duration,
+}
+
+struct Conn {
+    conn: LdapConn,
+    server: Url,
+    created_at: Instant,
+    last_used_at: Instant,
+}
+
+struct State {
+    idle: Vec<Conn>,
+    // Number of open connections, idle or checked out.
+    size: usize,
+}
+
+impl State {
+    fn record(&self) {
+        metrics::ldap_pool(self.idle.len(), self.size - self.idle.len());
+    }
+}
+
+// A bounded pool of connections already bound as the service account.
+pub struct Pool {
+    settings: PoolSettings,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+impl Pool {
+    pub fn new(settings: PoolSettings) -> Pool {
+        Pool {
+            settings,
+            state: Mutex::new(State {
+                idle: vec![],
+                size: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    // Check out a connection, reusing an idle one when possible and calling
+    // `connect` to open a new one otherwise.
+    pub fn get<F>(&self, connect: F) -> Result<PooledConn, Error>
+    where
+        F: FnOnce() -> Result<(LdapConn, Url), Error>,
+    {
+        let deadline = Instant::now() + self.settings.checkout_timeout;
+        let mut state = self.lock();
+
+        loop {
+            while let Some(mut conn) = state.idle.pop() {
+                if self.is_expired(&conn) {
+                    debug!("Closing expired LDAP connection");
+                    state.size -= 1;
+                    continue;
+                }
+
+                if conn.last_used_at.elapsed() >= self.settings.health_check_interval {
+                    // The health check needs the network, don’t hold the lock.
+                    drop(state);
+                    let healthy = self.is_healthy(&mut conn.conn);
+                    state = self.lock();
+
+                    if !healthy {
+                        debug!("Closing unhealthy LDAP connection");
+                        state.size -= 1;
+                        continue;
+                    }
+                }
+
+                state.record();
+
+                return Ok(PooledConn {
+                    pool: self,
+                    conn: Some(conn),
+                    broken: false,
+                });
+            }
+
+            if state.size < self.settings.max_size {
+                state.size += 1;
+                state.record();
+                drop(state);
+
+                return match connect() {
+                    Ok((conn, server)) => {
+                        let now = Instant::now();
+                        Ok(PooledConn {
+                            pool: self,
+                            conn: Some(Conn {
+                                conn,
+                                server,
+                                created_at: now,
+                                last_used_at: now,
+                            }),
+                            broken: false,
+                        })
+                    }
+                    Err(e) => {
+                        let mut state = self.lock();
+                        state.size -= 1;
+                        state.record();
+                        drop(state);
+
+                        self.released.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::PoolTimeout);
+            }
+
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .map(|(state, _)| state)
+                .unwrap_or_else(|e| e.into_inner().0);
+        }
+    }
+
+    fn release(&self, mut conn: Conn, broken: bool) {
+        let mut state = self.lock();
+
+        if broken || self.is_expired(&conn) {
+            state.size -= 1;
+        } else {
+            conn.last_used_at = Instant::now();
+            state.idle.push(conn);
+        }
+
+        state.record();
+        drop(state);
+
+        self.released.notify_one();
+    }
+
+    fn is_expired(&self, conn: &Conn) -> bool {
+        conn.created_at.elapsed() >= self.settings.max_lifetime
+            || conn.last_used_at.elapsed() >= self.settings.idle_timeout
+    }
+
+    fn is_healthy(&self, conn: &mut LdapConn) -> bool {
+        // Reading the root DSE is cheap and supported by every server.
+        conn.with_timeout(self.settings.timeout)
+            .search("", Scope::Base, "(objectClass=*)", vec!["1.1"])
+            .and_then(|r| r.success())
+            .is_ok()
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct PooledConn<'a> {
+    pool: &'a Pool,
+    conn: Option<Conn>,
+    broken: bool,
+}
+
+impl PooledConn<'_> {
+    // URL of the server this connection is opened to.
+    pub fn server(&self) -> &Url {
+        &self.conn.as_ref().unwrap().server
+    }
+
+    // Prevent the connection from going back to the pool, this must be called
+    // when an operation failed at the transport level.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+}
+
+impl Deref for PooledConn<'_> {
+    type Target = LdapConn;
+
+    fn deref(&self) -> &LdapConn {
+        &self.conn.as_ref().unwrap().conn
+    }
+}
+
+impl DerefMut for PooledConn<'_> {
+    fn deref_mut(&mut self) -> &mut LdapConn {
+        &mut self.conn.as_mut().unwrap().conn
+    }
+}
+
+impl Drop for PooledConn<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.release(conn, self.broken);
+        }
+    }
+}
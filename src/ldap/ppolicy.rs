@@ -0,0 +1,252 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Password policy control, as defined in draft-behera-ldap-password-policy.
+
+use ldap3::controls::{Control, RawControl};
+
+use super::Error;
+
+const OID: &str = "1.3.6.1.4.1.42.2.27.8.5.1";
+
+// BER tags used in the response value.
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_WARNING: u8 = 0xa0;
+const TAG_TIME_BEFORE_EXPIRATION: u8 = 0x80;
+const TAG_GRACE_AUTHNS_REMAINING: u8 = 0x81;
+const TAG_ERROR: u8 = 0x81;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Warning {
+    // Number of seconds before the password expires.
+    TimeBeforeExpiration(u64),
+    // Number of logins left with the expired password.
+    GraceLoginsRemaining(u64),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    pub warning: Option<Warning>,
+    pub error: Option<u64>,
+}
+
+impl Response {
+    // The error reported by the server when a bind failed.
+    pub fn to_error(&self) -> Option<Error> {
+        match self.error? {
+            0 => Some(Error::PasswordExpired),
+            1 => Some(Error::AccountLocked),
+            2 => Some(Error::PasswordMustReset),
+            _ => None,
+        }
+    }
+
+    // Whether the password was reset by an administrator and must be changed
+    // before doing anything else (the bind itself succeeds).
+    pub fn must_change(&self) -> bool {
+        self.error == Some(2)
+    }
+
+    // Why a new password was rejected.
+    pub fn rejection(&self) -> Option<&'static str> {
+        match self.error? {
+            3 => Some("password changes are not allowed"),
+            4 => Some("the current password must be supplied"),
+            5 => Some("the new password is not strong enough"),
+            6 => Some("the new password is too short"),
+            7 => Some("the password was changed too recently"),
+            8 => Some("the new password has already been used"),
+            _ => None,
+        }
+    }
+}
+
+pub fn request() -> RawControl {
+    RawControl {
+        ctype: OID.to_string(),
+        crit: false,
+        val: None,
+    }
+}
+
+// Find and decode the password policy response control.
+pub fn response(ctrls: &[Control]) -> Option<Response> {
+    let ctrl = ctrls.iter().find(|Control(_, raw)| raw.ctype == OID)?;
+
+    match ctrl.1.val.as_deref().map(decode) {
+        Some(Some(response)) => Some(response),
+        Some(None) => {
+            warn!("Unable to decode password policy response control");
+            None
+        }
+        None => Some(Response::default()),
+    }
+}
+
+fn decode(value: &[u8]) -> Option<Response> {
+    let (tag, mut content, _) = read_tlv(value)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+
+    let mut response = Response::default();
+
+    while !content.is_empty() {
+        let (tag, value, rest) = read_tlv(content)?;
+
+        match tag {
+            TAG_WARNING => {
+                let (tag, value, _) = read_tlv(value)?;
+                response.warning = match tag {
+                    TAG_TIME_BEFORE_EXPIRATION => {
+                        Some(Warning::TimeBeforeExpiration(integer(value)?))
+                    }
+                    TAG_GRACE_AUTHNS_REMAINING => {
+                        Some(Warning::GraceLoginsRemaining(integer(value)?))
+                    }
+                    _ => None,
+                };
+            }
+            TAG_ERROR => response.error = Some(integer(value)?),
+            _ => {}
+        }
+
+        content = rest;
+    }
+
+    Some(response)
+}
+
+// Split `data` into the tag, value and remaining bytes of its first element.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)?;
+
+    let (len, offset) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let len = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, 2 + count)
+    };
+
+    let end = offset.checked_add(len)?;
+    let value = data.get(offset..end)?;
+    Some((tag, value, &data[end..]))
+}
+
+// Values are all non-negative and fit in a u64.
+fn integer(value: &[u8]) -> Option<u64> {
+    if value.is_empty() || value.len() > 8 || value[0] & 0x80 != 0 {
+        return None;
+    }
+
+    Some(value.iter().fold(0u64, |n, b| (n << 8) | *b as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_errors() {
+        let response = decode(&[0x30, 0x03, 0x81, 0x01, 0x02]).unwrap();
+
+        assert_eq!(response.error, Some(2));
+        assert_eq!(response.warning, None);
+        assert!(response.must_change());
+        assert!(response.rejection().is_none());
+
+        let response = decode(&[0x30, 0x03, 0x81, 0x01, 0x05]).unwrap();
+        assert!(response.rejection().is_some());
+        assert!(response.to_error().is_none());
+    }
+
+    #[test]
+    fn decodes_warnings() {
+        assert_eq!(
+            decode(&[0x30, 0x05, 0xa0, 0x03, 0x80, 0x01, 0x3c]).unwrap(),
+            Response {
+                warning: Some(Warning::TimeBeforeExpiration(60)),
+                error: None,
+            }
+        );
+        assert_eq!(
+            decode(&[0x30, 0x05, 0xa0, 0x03, 0x81, 0x01, 0x03]).unwrap(),
+            Response {
+                warning: Some(Warning::GraceLoginsRemaining(3)),
+                error: None,
+            }
+        );
+        assert_eq!(
+            decode(&[0x30, 0x09, 0xa0, 0x04, 0x80, 0x02, 0x01, 0x00, 0x81, 0x01, 0x00]).unwrap(),
+            Response {
+                warning: Some(Warning::TimeBeforeExpiration(256)),
+                error: Some(0),
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_long_form_lengths() {
+        let response = decode(&[0x30, 0x81, 0x03, 0x81, 0x01, 0x01]).unwrap();
+        assert_eq!(response.error, Some(1));
+
+        assert_eq!(decode(&[0x30, 0x00]).unwrap(), Response::default());
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        assert_eq!(decode(&[]), None);
+        assert_eq!(decode(&[0x30]), None);
+        assert_eq!(decode(&[0x30, 0x05, 0x81, 0x01]), None);
+        assert_eq!(decode(&[0x30, 0x03, 0x81, 0x05, 0x02]), None);
+        assert_eq!(decode(&[0x30, 0x81]), None);
+        assert_eq!(decode(&[0x30, 0x02, 0xa0, 0x03, 0x80]), None);
+    }
+
+    #[test]
+    fn rejects_oversized_lengths() {
+        assert_eq!(decode(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00]), None);
+        assert_eq!(
+            decode(&[0x30, 0x85, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            None
+        );
+        // Indefinite lengths are not allowed in LDAP.
+        assert_eq!(decode(&[0x30, 0x80, 0x81, 0x01, 0x02, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn rejects_invalid_integers() {
+        assert_eq!(decode(&[0x30, 0x02, 0x81, 0x00]), None);
+        assert_eq!(decode(&[0x30, 0x03, 0x81, 0x01, 0xff]), None);
+        assert_eq!(
+            decode(&[0x30, 0x0b, 0x81, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_types() {
+        assert_eq!(decode(&[0x31, 0x00]), None);
+    }
+}
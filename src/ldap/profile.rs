@@ -0,0 +1,70 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+
+use super::groups::Nested;
+
+// Defaults for options left unset, depending on the kind of directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    // OpenLDAP and other RFC compliant directories using `inetOrgPerson` and
+    // `groupOfNames`.
+    Default,
+    ActiveDirectory,
+}
+
+impl Profile {
+    pub fn users_filter(self) -> &'static str {
+        match self {
+            Profile::Default => "(&(objectClass=inetOrgPerson)(|(uid={login})(mail={login})))",
+            Profile::ActiveDirectory => {
+                "(&(objectClass=user)(|(sAMAccountName={login})(userPrincipalName={login})))"
+            }
+        }
+    }
+
+    pub fn groups_filter(self) -> &'static str {
+        match self {
+            Profile::Default => "(&(objectClass=groupOfNames)(member={user_dn}))",
+            Profile::ActiveDirectory => "(&(objectClass=group)(member={user_dn}))",
+        }
+    }
+
+    pub fn groups_nested(self) -> Nested {
+        match self {
+            Profile::Default => Nested::None,
+            Profile::ActiveDirectory => Nested::ActiveDirectory,
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Default
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "default" => Ok(Profile::Default),
+            "active-directory" => Ok(Profile::ActiveDirectory),
+            _ => Err(format!("unknown LDAP profile: {}", value)),
+        }
+    }
+}
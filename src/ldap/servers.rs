@@ -0,0 +1,258 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use super::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // Always try servers in the order they were given.
+    Failover,
+    // Start with a different server for each request.
+    RoundRobin,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            _ => Err(format!("unknown server selection strategy: {}", value)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+struct Server {
+    url: Url,
+    breaker: Mutex<Breaker>,
+}
+
+pub struct Servers {
+    servers: Vec<Server>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    // Number of consecutive failures after which a server is skipped, 0
+    // disables circuit breaking.
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Servers {
+    pub fn new(urls: Vec<Url>, strategy: Strategy, threshold: u32, cooldown: Duration) -> Servers {
+        Servers {
+            servers: urls
+                .into_iter()
+                .map(|url| Server {
+                    url,
+                    breaker: Mutex::new(Breaker::default()),
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+            threshold,
+            cooldown,
+        }
+    }
+
+    // Call `f` with the URL of each available server until one of them
+    // answers. Errors that are not caused by the server being unreachable
+    // (e.g. invalid credentials) are returned right away.
+    pub fn try_each<T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&Url) -> Result<T, Error>,
+    {
+        let mut last_error: Option<Error> = None;
+
+        for server in self.candidates() {
+            match f(&server.url) {
+                Ok(v) => {
+                    debug!("LDAP request served by {}", server.url);
+                    self.record_success(server);
+                    return Ok(v);
+                }
+                Err(e) if e.is_server_failure() => {
+                    warn!("LDAP server {} failed: {}", server.url, e);
+                    self.record_failure(server);
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    debug!("LDAP request served by {}", server.url);
+                    self.record_success(server);
+                    return Err(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(Error::NoServerAvailable))
+    }
+
+    // Servers to try, in order. Servers whose circuit is open are skipped
+    // until their cooldown ends, unless every server is, in which case they
+    // are all tried rather than failing right away.
+    fn candidates(&self) -> Vec<&Server> {
+        let start = match self.strategy {
+            Strategy::Failover => 0,
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.servers.len(),
+        };
+
+        let ordered: Vec<&Server> = self.servers[start..]
+            .iter()
+            .chain(&self.servers[..start])
+            .collect();
+        let closed: Vec<&Server> = ordered
+            .iter()
+            .copied()
+            .filter(|s| !self.is_open(s))
+            .collect();
+
+        match closed.is_empty() {
+            true => ordered,
+            false => closed,
+        }
+    }
+
+    fn is_open(&self, server: &Server) -> bool {
+        let breaker = server.breaker.lock().unwrap_or_else(|e| e.into_inner());
+
+        match breaker.open_until {
+            Some(open_until) => Instant::now() < open_until,
+            None => false,
+        }
+    }
+
+    fn record_success(&self, server: &Server) {
+        let mut breaker = server.breaker.lock().unwrap_or_else(|e| e.into_inner());
+
+        if breaker.open_until.is_some() {
+            info!("LDAP server {} is available again", server.url);
+        }
+
+        *breaker = Breaker::default();
+    }
+
+    fn record_failure(&self, server: &Server) {
+        let mut breaker = server.breaker.lock().unwrap_or_else(|e| e.into_inner());
+
+        breaker.failures += 1;
+
+        if self.threshold > 0 && breaker.failures >= self.threshold {
+            warn!(
+                "Skipping LDAP server {} for {} seconds after {} consecutive failures",
+                server.url,
+                self.cooldown.as_secs(),
+                breaker.failures
+            );
+            breaker.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(threshold: u32) -> Servers {
+        let urls = vec![
+            "ldap://ldap1.example.org".parse().unwrap(),
+            "ldap://ldap2.example.org".parse().unwrap(),
+        ];
+
+        Servers::new(urls, Strategy::Failover, threshold, Duration::from_secs(60))
+    }
+
+    fn candidates(servers: &Servers) -> Vec<&str> {
+        servers
+            .candidates()
+            .into_iter()
+            .map(|s| s.url.host_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn skips_open_servers() {
+        let servers = servers(2);
+
+        servers.record_failure(&servers.servers[0]);
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap1.example.org", "ldap2.example.org"]
+        );
+
+        servers.record_failure(&servers.servers[0]);
+        assert_eq!(candidates(&servers), vec!["ldap2.example.org"]);
+
+        servers.record_success(&servers.servers[0]);
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap1.example.org", "ldap2.example.org"]
+        );
+    }
+
+    #[test]
+    fn tries_every_server_when_all_are_open() {
+        let servers = servers(1);
+
+        servers.record_failure(&servers.servers[0]);
+        servers.record_failure(&servers.servers[1]);
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap1.example.org", "ldap2.example.org"]
+        );
+    }
+
+    #[test]
+    fn never_opens_without_threshold() {
+        let servers = servers(0);
+
+        for _ in 0..10 {
+            servers.record_failure(&servers.servers[0]);
+        }
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap1.example.org", "ldap2.example.org"]
+        );
+    }
+
+    #[test]
+    fn rotates_with_round_robin() {
+        let urls = vec![
+            "ldap://ldap1.example.org".parse().unwrap(),
+            "ldap://ldap2.example.org".parse().unwrap(),
+        ];
+        let servers = Servers::new(urls, Strategy::RoundRobin, 0, Duration::from_secs(60));
+
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap1.example.org", "ldap2.example.org"]
+        );
+        assert_eq!(
+            candidates(&servers),
+            vec!["ldap2.example.org", "ldap1.example.org"]
+        );
+    }
+}
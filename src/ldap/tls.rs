@@ -0,0 +1,79 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use native_tls::{Certificate, Identity, TlsConnector};
+use std::fs;
+
+use super::Error;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+pub fn connector(
+    ca_file: Option<&str>,
+    cert_file: Option<&str>,
+    key_file: Option<&str>,
+    insecure_skip_verify: bool,
+) -> Result<TlsConnector, Error> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(ca_file) = ca_file {
+        for cert in read_certificates(ca_file)? {
+            builder.add_root_certificate(cert);
+        }
+    }
+
+    if let (Some(cert_file), Some(key_file)) = (cert_file, key_file) {
+        let cert = read(cert_file)?;
+        let key = read(key_file)?;
+
+        builder.identity(Identity::from_pkcs8(&cert, &key)?);
+    }
+
+    if insecure_skip_verify {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+// A CA file may contain a whole chain, while `Certificate::from_pem` only
+// reads the first certificate of its input.
+fn read_certificates(file: &str) -> Result<Vec<Certificate>, Error> {
+    let pem = String::from_utf8_lossy(&read(file)?).to_string();
+
+    let mut certs: Vec<Certificate> = vec![];
+
+    for block in pem.split_inclusive(PEM_CERTIFICATE_END) {
+        if !block.contains(PEM_CERTIFICATE_END) {
+            continue;
+        }
+
+        certs.push(Certificate::from_pem(block.trim().as_bytes())?);
+    }
+
+    if certs.is_empty() {
+        return Err(Error::InvalidTlsFile(
+            file.to_string(),
+            "no PEM certificate found".to_string(),
+        ));
+    }
+
+    Ok(certs)
+}
+
+fn read(file: &str) -> Result<Vec<u8>, Error> {
+    fs::read(file).map_err(|e| Error::InvalidTlsFile(file.to_string(), e.to_string()))
+}
@@ -13,10 +13,193 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use chrono::Utc;
+use chrono::{SecondsFormat, Utc};
 use log::{Metadata, Record, STATIC_MAX_LEVEL};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
 
-pub struct Logger;
+thread_local! {
+    // Fields added to every line logged by the current thread, i.e. while
+    // handling the current request.
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+// Add a field to the lines logged while handling the current request,
+// replacing any previous value.
+pub fn set_field(key: &'static str, value: &str) {
+    FIELDS.with(|fields| {
+        let mut fields = fields.borrow_mut();
+        fields.retain(|(k, _)| *k != key);
+        fields.push((key, value.to_string()));
+    });
+}
+
+pub fn clear_fields() {
+    FIELDS.with(|fields| fields.borrow_mut().clear());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Logfmt,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Format::Text),
+            "logfmt" => Ok(Format::Logfmt),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format: {}", value)),
+        }
+    }
+}
+
+// `stdout`, `stderr` or the path of a file logs are appended to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Stdout,
+    Stderr,
+    File(String),
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "stdout" => Ok(Output::Stdout),
+            "stderr" => Ok(Output::Stderr),
+            "" => Err("empty log output".to_string()),
+            path => Ok(Output::File(path.to_string())),
+        }
+    }
+}
+
+pub struct Logger {
+    format: Format,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(format: Format, output: &Output) -> io::Result<Logger> {
+        let output: Box<dyn Write + Send> = match output {
+            Output::Stdout => Box::new(io::stdout()),
+            Output::Stderr => Box::new(io::stderr()),
+            Output::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+
+        Ok(Logger {
+            format,
+            output: Mutex::new(output),
+        })
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let timestamp = Utc::now();
+        let fields = FIELDS
+            .try_with(|fields| fields.borrow().clone())
+            .unwrap_or_default();
+
+        match self.format {
+            Format::Text => {
+                let mut line = match (record.module_path(), record.line()) {
+                    (Some(module_path), Some(line)) => format!(
+                        "{} - {}#{} - {} - {}",
+                        timestamp,
+                        module_path,
+                        line,
+                        record.level(),
+                        record.args()
+                    ),
+                    _ => format!("{} - {} - {}", timestamp, record.level(), record.args()),
+                };
+
+                if !fields.is_empty() {
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, logfmt_value(v)))
+                        .collect();
+                    line.push_str(&format!(" [{}]", fields.join(" ")));
+                }
+
+                line
+            }
+            Format::Logfmt => {
+                let mut pairs: Vec<(&str, String)> = vec![
+                    ("ts", timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                    ("level", record.level().to_string().to_lowercase()),
+                ];
+
+                if let Some(module_path) = record.module_path() {
+                    pairs.push(("module", module_path.to_string()));
+                }
+
+                if let Some(line) = record.line() {
+                    pairs.push(("line", line.to_string()));
+                }
+
+                pairs.push(("msg", record.args().to_string()));
+                pairs.extend(fields.iter().map(|(k, v)| (*k, v.clone())));
+
+                pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, logfmt_value(v)))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            Format::Json => {
+                let mut object = Map::new();
+                object.insert(
+                    "ts".to_string(),
+                    Value::from(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                );
+                object.insert(
+                    "level".to_string(),
+                    Value::from(record.level().to_string().to_lowercase()),
+                );
+
+                if let Some(module_path) = record.module_path() {
+                    object.insert("module".to_string(), Value::from(module_path));
+                }
+
+                if let Some(line) = record.line() {
+                    object.insert("line".to_string(), Value::from(line));
+                }
+
+                object.insert("msg".to_string(), Value::from(record.args().to_string()));
+
+                for (k, v) in fields {
+                    object.insert(k.to_string(), Value::from(v));
+                }
+
+                Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+// Values are quoted when they contain spaces, quotes or equal signs.
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control())
+    {
+        return value.to_string();
+    }
+
+    format!("{:?}", value)
+}
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -28,19 +211,17 @@ impl log::Log for Logger {
             return;
         }
 
-        if record.module_path().is_some() && record.line().is_some() {
-            println!(
-                "{} - {}#{} - {} - {}",
-                Utc::now(),
-                record.module_path().unwrap(),
-                record.line().unwrap(),
-                record.level(),
-                record.args()
-            );
-        } else {
-            println!("{} - {} - {}", Utc::now(), record.level(), record.args());
-        }
+        let line = self.format(record);
+
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{}", line);
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        let _ = self
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush();
+    }
 }
@@ -0,0 +1,334 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SendableEmail, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("SMTP error: {0}")]
+    SmtpError(#[from] lettre::smtp::error::Error),
+
+    #[error("unable to build email: {0}")]
+    EmailError(#[from] lettre_email::error::Error),
+
+    #[error("TLS error: {0}")]
+    TlsError(#[from] native_tls::Error),
+
+    #[error("the email queue is closed")]
+    QueueClosed,
+
+    #[error("{0}")]
+    InvalidConfig(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    None,
+    StartTls,
+    Tls,
+}
+
+impl FromStr for Security {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Security::None),
+            "starttls" => Ok(Security::StartTls),
+            "tls" => Ok(Security::Tls),
+            _ => Err(format!("unknown SMTP security: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "smtp.host",
+        long = "smtp.host",
+        env = "SMTP_HOST",
+        hide_env_values = true,
+        value_name = "string",
+        default_value = "localhost",
+        help = "Host name of the SMTP relay",
+        display_order = 80
+    )]
+    host: String,
+
+    #[structopt(
+        name = "smtp.port",
+        long = "smtp.port",
+        env = "SMTP_PORT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "25",
+        help = "Port of the SMTP relay",
+        display_order = 81
+    )]
+    port: u16,
+
+    #[structopt(
+        name = "smtp.security",
+        long = "smtp.security",
+        env = "SMTP_SECURITY",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["none", "starttls", "tls"],
+        default_value = "none",
+        help = "How to secure the connection to the SMTP relay",
+        display_order = 82
+    )]
+    security: Security,
+
+    #[structopt(
+        name = "smtp.username",
+        long = "smtp.username",
+        env = "SMTP_USERNAME",
+        hide_env_values = true,
+        value_name = "string",
+        requires = "smtp.password",
+        help = "Username used to authenticate to the SMTP relay",
+        display_order = 83
+    )]
+    username: Option<String>,
+
+    #[structopt(
+        name = "smtp.password",
+        long = "smtp.password",
+        env = "SMTP_PASSWORD",
+        hide_env_values = true,
+        value_name = "string",
+        requires = "smtp.username",
+        help = "Password used to authenticate to the SMTP relay",
+        display_order = 84
+    )]
+    password: Option<String>,
+
+    #[structopt(
+        name = "smtp.from",
+        long = "smtp.from",
+        env = "SMTP_FROM",
+        hide_env_values = true,
+        value_name = "address",
+        default_value = "hydra-idp-ldap@localhost",
+        help = "Sender address of emails",
+        display_order = 85
+    )]
+    from: String,
+
+    #[structopt(
+        name = "smtp.timeout",
+        long = "smtp.timeout",
+        env = "SMTP_TIMEOUT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "10",
+        help = "Timeout in seconds for SMTP operations",
+        display_order = 86
+    )]
+    timeout: u64,
+}
+
+impl Opts {
+    pub fn check(&self) -> Result<(), Error> {
+        if self.timeout == 0 {
+            return Err(Error::InvalidConfig("the timeout can’t be 0".to_string()));
+        }
+
+        if self.security != Security::None {
+            TlsConnector::new()?;
+        }
+
+        // The sender address is only parsed when building emails.
+        EmailBuilder::new()
+            .to(self.from.as_str())
+            .from(self.from.as_str())
+            .text("")
+            .build()?;
+
+        Ok(())
+    }
+}
+
+pub struct Mailer {
+    from: String,
+    queue: Mutex<Sender<(String, SendableEmail)>>,
+}
+
+impl Mailer {
+    // Emails are sent in the background, so that responses don’t wait for
+    // the SMTP relay and their timing doesn’t tell whether one was sent.
+    pub fn new(opts: Opts) -> Mailer {
+        let (sender, receiver) = mpsc::channel::<(String, SendableEmail)>();
+        let from = opts.from.clone();
+
+        thread::spawn(move || {
+            for (to, email) in receiver {
+                match send(&opts, email) {
+                    Ok(()) => debug!("Sent email to `{}` through {}:{}", to, opts.host, opts.port),
+                    Err(e) => warn!("Unable to send email to `{}`: {}", to, e),
+                }
+            }
+        });
+
+        Mailer {
+            from,
+            queue: Mutex::new(sender),
+        }
+    }
+
+    pub fn queue(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        let email = EmailBuilder::new()
+            .to(to)
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(body)
+            .build()?;
+
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send((to.to_string(), email.into()))
+            .map_err(|_| Error::QueueClosed)
+    }
+}
+
+// A new connection is made for each email, they are only sent once in a
+// while.
+fn send(opts: &Opts, email: SendableEmail) -> Result<(), Error> {
+    let tls_parameters = || -> Result<ClientTlsParameters, Error> {
+        Ok(ClientTlsParameters::new(
+            opts.host.clone(),
+            TlsConnector::new()?,
+        ))
+    };
+
+    let security = match opts.security {
+        Security::None => ClientSecurity::None,
+        Security::StartTls => ClientSecurity::Required(tls_parameters()?),
+        Security::Tls => ClientSecurity::Wrapper(tls_parameters()?),
+    };
+
+    let client = SmtpClient::new((opts.host.as_str(), opts.port), security)?
+        .timeout(Some(Duration::from_secs(opts.timeout)));
+
+    let client = match (&opts.username, &opts.password) {
+        (Some(username), Some(password)) => {
+            client.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => client,
+    };
+
+    client.transport().send(email)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // A local SMTP server accepting a single email, returning the message.
+    fn sink() -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut message = String::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 OK\r\n").unwrap();
+                    } else {
+                        message.push_str(&line);
+                    }
+                    continue;
+                }
+
+                let command = line.to_uppercase();
+                if command.starts_with("DATA") {
+                    in_data = true;
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                }
+            }
+
+            message
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn sends_queued_emails() {
+        let (port, sink) = sink();
+
+        let mailer = Mailer::new(Opts {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: Security::None,
+            username: None,
+            password: None,
+            from: "idp@example.org".to_string(),
+            timeout: 10,
+        });
+
+        mailer
+            .queue(
+                "jdoe@example.org",
+                "Reset your password",
+                "Follow this link",
+            )
+            .unwrap();
+
+        let message = sink.join().unwrap();
+        assert!(message.contains("jdoe@example.org"));
+        assert!(message.contains("Reset your password"));
+        assert!(message.contains("Follow this link"));
+    }
+}
@@ -18,26 +18,50 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 #[macro_use]
+extern crate prometheus;
+#[macro_use]
 extern crate rocket;
 
+mod audit;
+mod config;
+mod hydra;
 mod ldap;
 mod logger;
+mod mail;
+mod metrics;
 mod parse;
 mod web;
 
 use anyhow::{Context, Result};
-use hydra_client::Hydra;
 use structopt::StructOpt;
 use url::Url;
 
+use crate::audit::Audit;
+use crate::hydra::Hydra;
 use crate::ldap::LDAP;
-use crate::logger::Logger;
+use crate::logger::{self, Logger};
+use crate::mail::Mailer;
 
 #[derive(Debug, StructOpt)]
 #[structopt(set_term_width = 0)]
 struct Opts {
+    #[structopt(
+        name = "config",
+        long = "config",
+        env = "CONFIG_FILE",
+        hide_env_values = true,
+        value_name = "file",
+        parse(try_from_str = parse::file),
+        help = "TOML or YAML file to read options from (options set on the command line or in the \
+                environment take precedence)",
+        display_order = 1
+    )]
+    config: Option<String>,
+
     #[structopt(
         name = "log.level",
         long = "log.level",
@@ -52,6 +76,34 @@ struct Opts {
     )]
     log_level: log::LevelFilter,
 
+    #[structopt(
+        name = "log.format",
+        long = "log.format",
+        env = "LOG_FORMAT",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["text", "logfmt", "json"],
+        default_value = "text",
+        help = "Log format",
+        display_order = 11,
+    )]
+    log_format: logger::Format,
+
+    #[structopt(
+        name = "log.output",
+        long = "log.output",
+        env = "LOG_OUTPUT",
+        hide_env_values = true,
+        value_name = "string",
+        default_value = "stdout",
+        help = "Where to write logs (`stdout`, `stderr` or the path of a file)",
+        display_order = 12
+    )]
+    log_output: logger::Output,
+
+    #[structopt(flatten)]
+    audit: audit::Opts,
+
     #[structopt(flatten)]
     web: web::Opts,
 
@@ -66,22 +118,70 @@ struct Opts {
     )]
     hydra_url: Url,
 
+    #[structopt(
+        name = "hydra.public-url",
+        long = "hydra.public-url",
+        env = "HYDRA_PUBLIC_URL",
+        hide_env_values = true,
+        value_name = "url",
+        help = "Public URL of Hydra, used to log the user out when switching accounts (enables \
+                switching accounts)",
+        display_order = 31
+    )]
+    hydra_public_url: Option<Url>,
+
     #[structopt(flatten)]
     ldap: ldap::Opts,
+
+    #[structopt(flatten)]
+    smtp: mail::Opts,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-static LOGGER: Logger = Logger;
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "check-config",
+        about = "Validate the configuration and print it, secrets being redacted"
+    )]
+    CheckConfig,
+}
 
 fn main() -> Result<()> {
-    let opts: Opts = Opts::from_args();
+    let args = config::args(std::env::args_os()).context("unable to load configuration file")?;
+    let matches = Opts::clap().get_matches_from(args);
+    let mut opts: Opts = Opts::from_clap(&matches);
 
-    log::set_logger(&LOGGER).context("unable to setup logger")?;
+    if let Some(Command::CheckConfig) = opts.command {
+        opts.web.set_ldap_profile(opts.ldap.profile());
+        web::check(&opts.web).context("invalid web configuration")?;
+        opts.smtp.check().context("invalid SMTP configuration")?;
+        LDAP::new(opts.ldap).context("invalid LDAP configuration")?;
+        print!("{}", config::effective(Opts::clap(), &matches)?);
+        return Ok(());
+    }
+
+    let logger =
+        Logger::new(opts.log_format, &opts.log_output).context("unable to open log file")?;
+    log::set_boxed_logger(Box::new(logger)).context("unable to setup logger")?;
     log::set_max_level(opts.log_level);
 
+    if let Some(config) = &opts.config {
+        info!("Read configuration file {}", config);
+    }
+
     debug!("Parsed arguments: {:?}", opts);
 
-    let hydra: Hydra = Hydra::new(opts.hydra_url);
-    let ldap: LDAP = LDAP::new(opts.ldap);
+    opts.web.set_ldap_profile(opts.ldap.profile());
+
+    let hydra: Hydra = Hydra::new(opts.hydra_url, opts.hydra_public_url);
+    let mut ldap: LDAP = LDAP::new(opts.ldap).context("invalid LDAP configuration")?;
+    ldap.set_binary_attrs(opts.web.binary_attrs());
+
+    let mailer: Mailer = Mailer::new(opts.smtp);
+    let audit: Audit = Audit::new(opts.audit).context("unable to open audit log")?;
 
-    web::launch(opts.web, hydra, ldap).context("Web server failed to start")
+    web::launch(opts.web, hydra, ldap, mailer, audit).context("Web server failed to start")
 }
@@ -0,0 +1,115 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Prometheus metrics, registered in the default registry.
+
+use prometheus::{Encoder, HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder};
+
+lazy_static! {
+    static ref LOGIN_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "hydra_idp_ldap_login_attempts_total",
+        "Number of login attempts by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    static ref CONSENT_ACCEPTED: IntCounterVec = register_int_counter_vec!(
+        "hydra_idp_ldap_consent_accepted_total",
+        "Number of accepted consent requests by client",
+        &["client_id"]
+    )
+    .unwrap();
+    static ref LDAP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "hydra_idp_ldap_ldap_request_duration_seconds",
+        "Duration of LDAP requests by operation",
+        &["operation"]
+    )
+    .unwrap();
+    static ref HYDRA_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "hydra_idp_ldap_hydra_request_duration_seconds",
+        "Duration of Hydra admin API requests by operation",
+        &["operation"]
+    )
+    .unwrap();
+    static ref LDAP_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "hydra_idp_ldap_ldap_pool_connections",
+        "Number of LDAP connections in the pool by state (idle or in use)",
+        &["state"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginOutcome {
+    Success,
+    UserNotFound,
+    InvalidCredentials,
+    // Refused because of the account (locked, expired password, etc.).
+    AccountError,
+    Throttled,
+    LdapError,
+    HydraError,
+}
+
+impl LoginOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::UserNotFound => "user_not_found",
+            LoginOutcome::InvalidCredentials => "invalid_credentials",
+            LoginOutcome::AccountError => "account_error",
+            LoginOutcome::Throttled => "throttled",
+            LoginOutcome::LdapError => "ldap_error",
+            LoginOutcome::HydraError => "hydra_error",
+        }
+    }
+}
+
+pub fn login_attempt(outcome: LoginOutcome) {
+    LOGIN_ATTEMPTS.with_label_values(&[outcome.as_str()]).inc();
+}
+
+pub fn consent_accepted(client_id: &str) {
+    CONSENT_ACCEPTED.with_label_values(&[client_id]).inc();
+}
+
+// The duration is observed when the returned timer is dropped.
+pub fn ldap_timer(operation: &str) -> HistogramTimer {
+    LDAP_REQUEST_DURATION
+        .with_label_values(&[operation])
+        .start_timer()
+}
+
+pub fn hydra_timer(operation: &str) -> HistogramTimer {
+    HYDRA_REQUEST_DURATION
+        .with_label_values(&[operation])
+        .start_timer()
+}
+
+pub fn ldap_pool(idle: usize, in_use: usize) {
+    LDAP_POOL_CONNECTIONS
+        .with_label_values(&["idle"])
+        .set(idle as i64);
+    LDAP_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(in_use as i64);
+}
+
+// Every registered metric in the Prometheus text format.
+pub fn gather() -> Result<String, prometheus::Error> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
@@ -14,24 +14,36 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use anyhow::Result;
-use hydra_client::Hydra;
 use rocket::config::{Config, Environment};
-use rocket::http::Status;
-use rocket::request::Form;
-use rocket::response::Redirect;
-use rocket::{Request, State};
+use rocket::http::{ContentType, Cookies, Status};
+use rocket::request::{self, Form, FromRequest};
+use rocket::response::{content, Redirect};
+use rocket::{Outcome, Request, State};
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
-use serde_json::{from_value, json, Value};
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use structopt::StructOpt;
 
-use crate::ldap::LDAP;
+use crate::audit::{self, Audit};
+use crate::hydra::{Hydra, LoginRequest, RejectRequest};
+use crate::ldap::{self, PasswordWarning, LDAP};
+use crate::logger;
+use crate::mail::Mailer;
+use crate::metrics::{self, LoginOutcome};
 use crate::parse;
 
+mod consent;
+mod csrf;
 mod health;
+mod password;
+mod prompt;
+mod request_id;
+mod reset;
+mod subject;
+mod throttle;
 
 const STATIC_DIR: &str = "assets/static/";
 const TEMPLATE_DIR: &str = "assets/templates/";
@@ -90,8 +102,52 @@ pub struct Opts {
     )]
     base_path: String,
 
+    #[structopt(
+        name = "metrics.listen-address",
+        long = "metrics.listen-address",
+        env = "METRICS_LISTEN_ADDRESS",
+        hide_env_values = true,
+        value_name = "address",
+        parse(try_from_str = parse::sock_addr),
+        help = "Address to serve metrics on, apart from the web server (in the form <ip>:<port>, \
+                metrics aren’t served when unset)",
+        display_order = 24,
+    )]
+    metrics_listen_address: Option<SocketAddr>,
+
+    #[structopt(
+        name = "web.trusted-proxies",
+        long = "web.trusted-proxies",
+        env = "WEB_TRUSTED_PROXIES",
+        hide_env_values = true,
+        value_name = "list",
+        use_delimiter = true,
+        help = "Comma separated list of reverse proxy IP addresses allowed to set the client’s \
+                address with the `X-Real-IP` header",
+        display_order = 25
+    )]
+    trusted_proxies: Vec<IpAddr>,
+
     #[structopt(flatten)]
     oauth: OauthOpts,
+
+    #[structopt(flatten)]
+    consent: consent::Opts,
+
+    #[structopt(flatten)]
+    password: password::Opts,
+
+    #[structopt(flatten)]
+    reset: reset::Opts,
+
+    #[structopt(flatten)]
+    throttle: throttle::Opts,
+
+    #[structopt(flatten)]
+    health: health::Opts,
+
+    #[structopt(flatten)]
+    csrf: csrf::Opts,
 }
 
 #[derive(Debug, StructOpt)]
@@ -105,7 +161,7 @@ pub struct OauthOpts {
         default_value = "0",
         help = "Time in seconds defining how long a sucessful login should be remembered (0 means \
                 it will be until browser tab or window is closed).",
-        display_order = 50
+        display_order = 60
     )]
     login_remember_for: u64,
 
@@ -118,7 +174,7 @@ pub struct OauthOpts {
         parse(try_from_str = parse::comma_separated_key_value),
         default_value = "cn:name,sn:family_name,givenName:given_name,mail:email",
         help = "A list of comma separated <LDAP attribute name>:<OAuth claim name>",
-        display_order = 51,
+        display_order = 61,
     )]
     attrs_map: HashMap<String, String>,
 
@@ -131,70 +187,293 @@ pub struct OauthOpts {
         parse(try_from_str = parse::comma_separated_key_value),
         default_value = "name:profile,family_name:profile,given_name:profile,email:email",
         help = "A list of comma separated <OAuth claim name>:<OAuth scope name>",
-        display_order = 52,
+        display_order = 62,
     )]
     claims_map: HashMap<String, String>,
+
+    #[structopt(
+        name = "oauth.subject-attribute",
+        long = "oauth.subject-attribute",
+        env = "OAUTH_SUBJECT_ATTRIBUTE",
+        hide_env_values = true,
+        value_name = "string",
+        help = "LDAP attribute used as the OAuth subject [default: entryUUID, objectGUID with the \
+                active-directory LDAP profile]",
+        display_order = 63
+    )]
+    subject_attribute: Option<String>,
+
+    #[structopt(
+        name = "oauth.subject-format",
+        long = "oauth.subject-format",
+        env = "OAUTH_SUBJECT_FORMAT",
+        hide_env_values = true,
+        value_name = "string",
+        possible_values = &["string", "guid"],
+        help = "How to format the subject attribute (`string` uses the value as is, binary \
+                values being base64 encoded, `guid` formats a binary value as a GUID) [default: \
+                string, guid with the active-directory LDAP profile]",
+        display_order = 64
+    )]
+    subject_format: Option<subject::Format>,
+
+    #[structopt(
+        name = "oauth.subject-pairwise-secret",
+        long = "oauth.subject-pairwise-secret",
+        env = "OAUTH_SUBJECT_PAIRWISE_SECRET",
+        hide_env_values = true,
+        value_name = "string",
+        help = "Secret used to derive a different subject for each client (enables pairwise \
+                subject identifiers)",
+        display_order = 65
+    )]
+    subject_pairwise_secret: Option<String>,
+
+    #[structopt(skip)]
+    ldap_profile: ldap::Profile,
+}
+
+impl Opts {
+    // Options left unset default to values depending on the LDAP profile.
+    pub fn set_ldap_profile(&mut self, profile: ldap::Profile) {
+        self.oauth.ldap_profile = profile;
+    }
+
+    // Attributes the LDAP server must return as binary values.
+    pub fn binary_attrs(&self) -> Vec<String> {
+        match self.oauth.subject_format() {
+            subject::Format::Guid => vec![self.oauth.subject_attribute().to_string()],
+            subject::Format::String => vec![],
+        }
+    }
+}
+
+impl OauthOpts {
+    fn subject_attribute(&self) -> &str {
+        match (&self.subject_attribute, self.ldap_profile) {
+            (Some(attribute), _) => attribute.as_str(),
+            (None, ldap::Profile::Default) => "entryUUID",
+            (None, ldap::Profile::ActiveDirectory) => "objectGUID",
+        }
+    }
+
+    fn subject_format(&self) -> subject::Format {
+        match (self.subject_format, self.ldap_profile) {
+            (Some(format), _) => format,
+            (None, ldap::Profile::Default) => subject::Format::String,
+            (None, ldap::Profile::ActiveDirectory) => subject::Format::Guid,
+        }
+    }
+
+    // The subject identifier sent to `client_id`, when pairwise subject
+    // identifiers are enabled.
+    fn pairwise_subject(&self, client_id: &str, subject: &str) -> Option<String> {
+        self.subject_pairwise_secret
+            .as_ref()
+            .map(|secret| subject::pairwise(secret, client_id, subject))
+    }
 }
 
-pub fn launch(opts: Opts, hydra: Hydra, ldap: LDAP) -> Result<()> {
+fn config(opts: &Opts) -> Result<Config> {
     let config_builder = Config::build(Environment::Production)
         .address(opts.listen_address.ip().to_string())
         .port(opts.listen_address.port())
         .extra("template_dir", TEMPLATE_DIR);
 
-    let config_builder = match opts.tls_cert_file.is_some() && opts.tls_key_file.is_some() {
-        true => config_builder.tls(opts.tls_cert_file.unwrap(), opts.tls_key_file.unwrap()),
-        false => config_builder,
+    let config_builder = match (&opts.tls_cert_file, &opts.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => config_builder.tls(cert_file, key_file),
+        _ => config_builder,
     };
 
-    let config = match config_builder.finalize() {
-        Ok(config) => config,
-        Err(_) => {
-            // This is the only possible cause of error
-            return Err(anyhow!("Unable to read TLS certificate or private key."));
-        }
-    };
+    match config_builder.finalize() {
+        Ok(config) => Ok(config),
+        // This is the only possible cause of error
+        Err(_) => Err(anyhow!("Unable to read TLS certificate or private key.")),
+    }
+}
+
+// Validate the options without starting anything.
+pub fn check(opts: &Opts) -> Result<()> {
+    config(opts)?;
+
+    opts.consent
+        .check()
+        .map_err(|e| anyhow!("invalid consent configuration: {}", e))?;
+    opts.reset
+        .check()
+        .map_err(|e| anyhow!("invalid password reset configuration: {}", e))?;
+    opts.throttle
+        .check()
+        .map_err(|e| anyhow!("invalid throttling configuration: {}", e))?;
+
+    Ok(())
+}
+
+pub fn launch(opts: Opts, hydra: Hydra, ldap: LDAP, mailer: Mailer, audit: Audit) -> Result<()> {
+    let config = config(&opts)?;
+    let tls = opts.tls_cert_file.is_some() && opts.tls_key_file.is_some();
+
+    let throttle = throttle::Throttle::new(opts.throttle)
+        .map_err(|e| anyhow!("Unable to setup login throttling: {}", e))?;
+
+    let routes = routes![
+        login,
+        post_login,
+        prompt::post_select_account,
+        password::password,
+        password::post_password,
+        reset::reset,
+        reset::post_reset,
+        reset::confirm,
+        reset::post_confirm,
+        consent::consent,
+        consent::post_consent,
+        logout,
+        post_logout,
+        error
+    ];
+
+    if let Some(address) = opts.metrics_listen_address {
+        launch_metrics(address)?;
+    }
 
     let health_path = Path::new(opts.base_path.as_str()).join("/health/");
     let static_path = Path::new(opts.base_path.as_str()).join("/static/");
 
     let rocket = rocket::custom(config)
-        .mount(
-            opts.base_path.as_str(),
-            routes![login, post_login, consent, logout, post_logout, error],
-        )
+        .mount(opts.base_path.as_str(), routes)
         .mount(
             health_path.to_str().unwrap(),
             routes![health::live, health::ready],
         )
         .mount(static_path.to_str().unwrap(), StaticFiles::from(STATIC_DIR))
-        .register(catchers![not_found, internal_server_error])
+        .register(catchers![forbidden, not_found, internal_server_error])
+        .manage(TrustedProxies(opts.trusted_proxies))
         .manage(opts.oauth)
+        .manage(opts.consent)
+        .manage(opts.password)
+        .manage(reset::Reset::new(opts.reset))
+        .manage(throttle)
+        .manage(health::Readiness::new(opts.health))
+        .manage(csrf::Csrf::new(opts.csrf, opts.base_path.as_str(), tls))
         .manage(hydra)
         .manage(ldap)
+        .manage(mailer)
+        .manage(audit)
+        .attach(request_id::RequestId)
         .attach(Template::fairing());
 
     // rocket.launch only exits on error
     Err(anyhow!(rocket.launch()))
 }
 
+// Serve metrics on their own address, in the background.
+fn launch_metrics(address: SocketAddr) -> Result<()> {
+    let config = Config::build(Environment::Production)
+        .address(address.ip().to_string())
+        .port(address.port())
+        .finalize()
+        .map_err(|e| anyhow!("Invalid metrics listen address: {}", e))?;
+
+    std::thread::spawn(move || {
+        let e = rocket::custom(config)
+            .mount("/", routes![get_metrics])
+            .launch();
+        error!("Metrics server failed: {}", e);
+    });
+
+    Ok(())
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Responder)]
-enum Response {
+pub enum Response {
     Template(Template),
     Redirect(Redirect),
     Status(Status),
 }
 
+// Reverse proxies whose `X-Real-IP` header is trusted.
+struct TrustedProxies(Vec<IpAddr>);
+
+// The client making the request, its address being taken from the
+// `X-Real-IP` header only when the request comes from a trusted reverse
+// proxy.
+pub struct Client {
+    ip: IpAddr,
+    user_agent: Option<String>,
+}
+
+fn client_ip(
+    remote: Option<IpAddr>,
+    real_ip: Option<IpAddr>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    match remote {
+        Some(remote) if trusted.contains(&remote) => real_ip.or(Some(remote)),
+        remote => remote,
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Client {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let trusted = match request.guard::<State<TrustedProxies>>() {
+            Outcome::Success(trusted) => trusted.inner().0.as_slice(),
+            _ => &[],
+        };
+
+        let remote = request.remote().map(|address| address.ip());
+
+        match client_ip(remote, request.real_ip(), trusted) {
+            Some(ip) => Outcome::Success(Client {
+                ip,
+                user_agent: request.headers().get_one("User-Agent").map(String::from),
+            }),
+            None => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+// Count a failed attempt against `login` and `client`, auditing the lockout
+// it may start.
+fn record_failure(throttle: &throttle::Throttle, audit: &Audit, login: &str, client: &Client) {
+    if throttle.failure(login, client.ip).is_some() {
+        audit.record(
+            audit::Event::new(audit::Kind::Lockout, audit::Outcome::Success)
+                .source(client.ip, client.user_agent.as_deref())
+                .login(login)
+                .reason("too_many_failures"),
+        );
+    }
+}
+
 #[derive(FromForm)]
 struct LoginForm {
     login: String,
     password: String,
     remember: Option<bool>,
+    csrf_token: csrf::FormToken,
 }
 
-fn render_login_template(form_error: Option<String>) -> Template {
+// `login` prefills the login field. The login challenge is passed along to
+// the password change page, which logs the user in once done.
+fn render_login_template(
+    csrf_token: &str,
+    login_challenge: &str,
+    login: &str,
+    reset_enabled: bool,
+    form_error: Option<String>,
+) -> Template {
     let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+    context.insert("login_challenge".to_string(), login_challenge.to_string());
+    context.insert("login".to_string(), login.to_string());
+
+    if reset_enabled {
+        context.insert("reset_enabled".to_string(), "true".to_string());
+    }
 
     if let Some(form_error) = form_error {
         context.insert("form_error".to_string(), form_error);
@@ -203,14 +482,173 @@ fn render_login_template(form_error: Option<String>) -> Template {
     Template::render("login", &context)
 }
 
-#[get("/login?<login_challenge>")]
-fn login(login_challenge: String, hydra: State<Hydra>) -> Response {
-    let hydra = hydra.clone();
+// Shown instead of checking the credentials when there were too many failed
+// attempts, `delay` being the number of seconds before the next one.
+fn render_throttled_login_template(
+    csrf_token: &str,
+    login_challenge: &str,
+    login: &str,
+    reset_enabled: bool,
+    delay: u64,
+) -> Template {
+    let delay = match delay {
+        0..=59 => "a minute".to_string(),
+        60..=3599 => format!("{} minutes", (delay + 59) / 60),
+        _ => format!("{} hours", (delay + 3599) / 3600),
+    };
+
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+    context.insert("login_challenge".to_string(), login_challenge.to_string());
+    context.insert("login".to_string(), login.to_string());
 
+    if reset_enabled {
+        context.insert("reset_enabled".to_string(), "true".to_string());
+    }
+
+    context.insert(
+        "form_warning".to_string(),
+        format!(
+            "Too many failed login attempts, please try again in {}.",
+            delay
+        ),
+    );
+
+    Template::render("login", &context)
+}
+
+// Shown after a successful login when the password is about to expire, the
+// user then continues to `redirect_to`.
+fn render_password_warning_template(warning: PasswordWarning, redirect_to: String) -> Template {
+    let warning = match warning {
+        PasswordWarning::TimeBeforeExpiration(seconds) => match seconds / 86400 {
+            0 => "Your password expires in less than a day.".to_string(),
+            1 => "Your password expires in 1 day.".to_string(),
+            days => format!("Your password expires in {} days.", days),
+        },
+        PasswordWarning::GraceLoginsRemaining(1) => {
+            "Your password has expired, you have 1 grace login left.".to_string()
+        }
+        PasswordWarning::GraceLoginsRemaining(logins) => format!(
+            "Your password has expired, you have {} grace logins left.",
+            logins
+        ),
+    };
+
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("warning".to_string(), warning);
+    context.insert("redirect_to".to_string(), redirect_to);
+
+    Template::render("password-warning", &context)
+}
+
+// The OAuth 2.0 error sent back to the client when the user can’t be
+// authenticated because of `e`, an error unrelated to their account.
+fn ldap_rejection(e: &ldap::Error) -> RejectRequest {
+    match e {
+        ldap::Error::PoolTimeout => RejectRequest::new(
+            "temporarily_unavailable",
+            "The authentication server is overloaded, please try again later.",
+            503,
+        ),
+        e if e.is_server_failure() => RejectRequest::new(
+            "temporarily_unavailable",
+            "The authentication server is unavailable, please try again later.",
+            503,
+        ),
+        _ => RejectRequest::new(
+            "server_error",
+            "An unexpected error occurred while authenticating the user.",
+            500,
+        ),
+    }
+}
+
+// The OAuth 2.0 error sent back to the client when its request can’t be
+// completed because of an unexpected error.
+fn server_error() -> RejectRequest {
+    RejectRequest::new(
+        "server_error",
+        "An unexpected error occurred while processing the request.",
+        500,
+    )
+}
+
+// Send the user back to the client with an OAuth 2.0 error instead of leaving
+// them on an error page. This needs Hydra, failures to reach it still end
+// with an internal server error.
+fn reject_login(hydra: &Hydra, login_challenge: &str, reject: RejectRequest) -> Response {
+    match hydra.reject_login_request(login_challenge.to_string(), &reject) {
+        Ok(r) => {
+            info!(
+                "rejected login request with challenge `{}`: {}",
+                login_challenge, reject.error
+            );
+            Response::Redirect(Redirect::to(r.redirect_to))
+        }
+        Err(e) => {
+            warn!("unable to reject login request: {}", e);
+            Response::Status(Status::InternalServerError)
+        }
+    }
+}
+
+// Count the login attempt and add its outcome to the request’s log lines.
+fn record_login_attempt(outcome: LoginOutcome) {
+    metrics::login_attempt(outcome);
+    logger::set_field("outcome", outcome.as_str());
+}
+
+fn login_outcome(e: &ldap::Error) -> LoginOutcome {
+    match e {
+        ldap::Error::UserNotFound(_) => LoginOutcome::UserNotFound,
+        ldap::Error::InvalidCredentials => LoginOutcome::InvalidCredentials,
+        e => match login_error_message(e) {
+            Some(_) => LoginOutcome::AccountError,
+            None => LoginOutcome::LdapError,
+        },
+    }
+}
+
+// Message shown to the user when logging in failed because of their account
+// or credentials, `None` for other errors.
+fn login_error_message(e: &ldap::Error) -> Option<&'static str> {
+    match e {
+        ldap::Error::UserNotFound(_) | ldap::Error::InvalidCredentials => {
+            Some("Invalid login or password.")
+        }
+        ldap::Error::LoginRestricted => {
+            Some("You are not allowed to log in at this time or from this device.")
+        }
+        ldap::Error::PasswordExpired => Some("Your password has expired and must be changed."),
+        ldap::Error::PasswordMustReset => Some(
+            "Your password must be changed but can’t be used to log in anymore, please reset it \
+             or contact the site administrator.",
+        ),
+        ldap::Error::AccountDisabled | ldap::Error::AccountExpired => {
+            Some("Your account is disabled, please contact the site administrator.")
+        }
+        ldap::Error::AccountLocked => Some(
+            "Your account is locked, please try again later or contact the site administrator.",
+        ),
+        _ => None,
+    }
+}
+
+#[get("/login?<login_challenge>")]
+fn login(
+    login_challenge: String,
+    csrf_token: csrf::Token,
+    oauth_opts: State<OauthOpts>,
+    hydra: State<Hydra>,
+    reset: State<reset::Reset>,
+) -> Response {
     if login_challenge.is_empty() {
         return Response::Status(Status::NotFound);
     }
 
+    logger::set_field("challenge", login_challenge.as_str());
+
     let r = match hydra.get_login_request(login_challenge.clone()) {
         Ok(r) => r,
         Err(e) => {
@@ -219,200 +657,378 @@ fn login(login_challenge: String, hydra: State<Hydra>) -> Response {
         }
     };
 
+    logger::set_field("client_id", r.client.client_id.as_str());
+
+    let prompt = prompt::Prompt::from_request(&r);
+
+    if !r.skip && prompt.none {
+        return reject_login(
+            &hydra,
+            login_challenge.as_str(),
+            RejectRequest::new("login_required", "The user must log in.", 401),
+        );
+    }
+
     if r.skip {
-        return match hydra.accept_login_request(
-            login_challenge,
+        logger::set_field("subject", r.subject.as_str());
+
+        return match prompt.select_account {
+            true => Response::Template(prompt::render_select_account_template(
+                csrf_token.as_str(),
+                login_challenge.as_str(),
+                r.subject.as_str(),
+                hydra.browser_logout_url().is_some(),
+            )),
+            false => accept_session(login_challenge, r, &oauth_opts, &hydra),
+        };
+    }
+
+    Response::Template(render_login_template(
+        csrf_token.as_str(),
+        login_challenge.as_str(),
+        r.oidc_context.login_hint.as_str(),
+        reset.enabled(),
+        None,
+    ))
+}
+
+// Accept the login request for the user already logged in, without asking
+// for their credentials.
+fn accept_session(
+    login_challenge: String,
+    r: LoginRequest,
+    oauth_opts: &OauthOpts,
+    hydra: &Hydra,
+) -> Response {
+    let pairwise_subject = oauth_opts.pairwise_subject(&r.client.client_id, &r.subject);
+
+    match hydra.timed("accept_login_request", |h| {
+        h.accept_login_request(
+            login_challenge.clone(),
             r.subject,
             None,
             Some(r.context),
+            pairwise_subject,
             None,
             None,
-            None,
-        ) {
-            Ok(r) => Response::Redirect(Redirect::to(r.redirect_to)),
-            Err(e) => {
-                warn!("unable to accept login request: {}", e);
-                Response::Status(Status::InternalServerError)
-            }
-        };
+        )
+    }) {
+        Ok(r) => Response::Redirect(Redirect::to(r.redirect_to)),
+        Err(e) => {
+            warn!("unable to accept login request: {}", e);
+            reject_login(hydra, login_challenge.as_str(), server_error())
+        }
     }
-
-    Response::Template(render_login_template(None))
 }
 
 #[post("/login?<login_challenge>", data = "<form>")]
 fn post_login(
     login_challenge: String,
     form: Form<LoginForm>,
+    csrf_token: csrf::Token,
+    client: Client,
     oauth_opts: State<OauthOpts>,
     hydra: State<Hydra>,
     ldap: State<LDAP>,
+    throttle: State<throttle::Throttle>,
+    audit: State<Audit>,
+    reset: State<reset::Reset>,
 ) -> Response {
     if login_challenge.is_empty() {
         return Response::Status(Status::NotFound);
     }
 
+    logger::set_field("challenge", login_challenge.as_str());
+
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    let attempt = LoginAttempt {
+        login_challenge: login_challenge.as_str(),
+        login: form.login.as_str(),
+        password: form.password.as_str(),
+        remember: form.remember,
+        client: &client,
+        csrf_token: &csrf_token,
+        reset_enabled: reset.enabled(),
+    };
+
+    login_user(attempt, &oauth_opts, &hydra, &ldap, &throttle, &audit)
+}
+
+struct LoginAttempt<'a> {
+    login_challenge: &'a str,
+    login: &'a str,
+    password: &'a str,
+    remember: Option<bool>,
+    client: &'a Client,
+    csrf_token: &'a csrf::Token,
+    // Whether the login page links to the password reset page.
+    reset_enabled: bool,
+}
+
+// Authenticate the user and accept the login request. Users whose password
+// must be changed are sent to the password change page first.
+fn login_user(
+    attempt: LoginAttempt,
+    oauth_opts: &OauthOpts,
+    hydra: &Hydra,
+    ldap: &LDAP,
+    throttle: &throttle::Throttle,
+    audit: &Audit,
+) -> Response {
+    let LoginAttempt {
+        login_challenge,
+        login,
+        password,
+        remember,
+        client,
+        csrf_token,
+        reset_enabled,
+    } = attempt;
+
+    let audit_event = |outcome: audit::Outcome| {
+        audit::Event::new(audit::Kind::Login, outcome)
+            .source(client.ip, client.user_agent.as_deref())
+            .login(login)
+            .challenge(login_challenge)
+    };
+
+    if let Some(delay) = throttle.check(login, client.ip) {
+        info!(
+            "Refusing login attempt for {} from {}: throttled for {}s",
+            login, client.ip, delay
+        );
+        record_login_attempt(LoginOutcome::Throttled);
+        audit.record(audit_event(audit::Outcome::Failure).reason(LoginOutcome::Throttled.as_str()));
+        return Response::Template(render_throttled_login_template(
+            csrf_token.as_str(),
+            login_challenge,
+            login,
+            reset_enabled,
+            delay,
+        ));
+    }
+
     let mut search_attrs: Vec<String> = oauth_opts.attrs_map.keys().cloned().collect();
+    search_attrs.push(oauth_opts.subject_attribute().to_string());
     search_attrs.push("+".to_string());
 
-    let attrs = match ldap.get_user_attrs(form.login.as_str(), search_attrs) {
-        Ok(attrs) => attrs,
-        Err(e) => {
-            warn!("Unable to find user in LDAP database: {}", e);
-            return Response::Template(render_login_template(Some(
-                "Invalid login or password.".to_string(),
-            )));
+    let user = match ldap.login(login, password, search_attrs) {
+        Ok(user) => user,
+        Err(ldap::Error::PasswordMustChange) => {
+            record_login_attempt(LoginOutcome::AccountError);
+            audit.record(
+                audit_event(audit::Outcome::Failure)
+                    .reason(ldap::Error::PasswordMustChange.reason()),
+            );
+            info!("Password of {} must be changed", login);
+            return Response::Template(password::render_template(
+                csrf_token.as_str(),
+                login,
+                Some(login_challenge),
+                remember,
+                Some("Your password must be changed.".to_string()),
+                None,
+            ));
         }
-    };
+        Err(e) => match login_error_message(&e) {
+            Some(message) => {
+                record_login_attempt(login_outcome(&e));
+                audit.record(audit_event(audit::Outcome::Failure).reason(e.reason()));
 
-    match ldap.validate_credentials(attrs["dn"].as_str().unwrap(), form.password.as_str()) {
-        Ok(ok) => {
-            if !ok {
-                info!("Invalid login or password for {}", form.login);
-                return Response::Template(render_login_template(Some(
-                    "Invalid login or password.".to_string(),
-                )));
+                if let ldap::Error::UserNotFound(_) | ldap::Error::InvalidCredentials = e {
+                    record_failure(throttle, audit, login, client);
+                }
+
+                info!("Unable to log {} in: {}", login, e);
+                return Response::Template(render_login_template(
+                    csrf_token.as_str(),
+                    login_challenge,
+                    login,
+                    reset_enabled,
+                    Some(message.to_string()),
+                ));
             }
-        }
-        Err(e) => {
-            warn!("LDAP Error: {}", e);
-            return Response::Status(Status::InternalServerError);
-        }
+            None => {
+                record_login_attempt(LoginOutcome::LdapError);
+                warn!("LDAP Error: {}", e);
+                return reject_login(hydra, login_challenge, ldap_rejection(&e));
+            }
+        },
     };
 
-    let mut context: HashMap<String, Value> = HashMap::new();
-    context.insert("attrs".to_string(), json!(attrs));
+    throttle.success(login);
 
-    match hydra.accept_login_request(
-        login_challenge.clone(),
-        // XXX: this line feels ugly, but I don’t know how to make it better.
-        // Problem is serde_json::Value::to_string() return a double quoted string.
-        attrs["entryUUID"].as_str().unwrap().to_string(),
-        None,
-        Some(context),
-        None,
-        form.remember,
-        Some(oauth_opts.login_remember_for),
+    let subject = match subject::from_attrs(
+        &user.attrs,
+        oauth_opts.subject_attribute(),
+        oauth_opts.subject_format(),
     ) {
-        Ok(r) => {
-            info!(
-                "accepted login request with challenge `{}` for `{}`",
-                login_challenge, form.login
-            );
-            Response::Redirect(Redirect::to(r.redirect_to))
+        Ok(subject) => {
+            logger::set_field("subject", subject.as_str());
+            subject
         }
         Err(e) => {
-            warn!("unable to accept login request: {}", e);
-            Response::Status(Status::InternalServerError)
+            record_login_attempt(LoginOutcome::AccountError);
+            audit.record(audit_event(audit::Outcome::Failure).reason(e.reason()));
+            warn!("Unable to get subject for {}: {}", login, e);
+            return Response::Template(render_login_template(
+                csrf_token.as_str(),
+                login_challenge,
+                login,
+                reset_enabled,
+                Some(
+                    "Your account can’t be used to log in, please contact the site administrator."
+                        .to_string(),
+                ),
+            ));
         }
-    }
-}
-
-#[get("/consent?<consent_challenge>")]
-fn consent(
-    consent_challenge: String,
-    oauth_opts: State<OauthOpts>,
-    hydra: State<Hydra>,
-) -> Response {
-    let hydra = hydra.clone();
-
-    if consent_challenge.is_empty() {
-        return Response::Status(Status::NotFound);
-    }
+    };
 
-    let r = match hydra.get_consent_request(consent_challenge.clone()) {
+    // The login request is only fetched once the credentials are checked,
+    // so throttled attempts don’t reach Hydra.
+    let r = match hydra.get_login_request(login_challenge.to_string()) {
         Ok(r) => r,
         Err(e) => {
-            warn!("Unable to get consent request details: {}", e);
+            record_login_attempt(LoginOutcome::HydraError);
+            warn!("unable to get login request details: {}", e);
             return Response::Status(Status::InternalServerError);
         }
     };
 
-    if !r.context.contains_key("attrs") {
-        warn!("Unable to get attrs from consent request’s context.");
-        return Response::Status(Status::InternalServerError);
+    let client_id = r.client.client_id.as_str();
+    logger::set_field("client_id", client_id);
+
+    // Hydra can only accept the login request for the user of the current
+    // session, e.g. when someone logged in from another tab in the meantime.
+    if r.skip && subject != r.subject {
+        record_login_attempt(LoginOutcome::AccountError);
+        audit.record(
+            audit_event(audit::Outcome::Failure)
+                .client_id(client_id)
+                .reason("another_user_session"),
+        );
+        info!("Refusing login of {}: another user is logged in", login);
+        return Response::Template(render_login_template(
+            csrf_token.as_str(),
+            login_challenge,
+            login,
+            reset_enabled,
+            Some(
+                "You are already logged in with another account, please log in with it or log \
+                 out first."
+                    .to_string(),
+            ),
+        ));
     }
 
-    let attrs: HashMap<String, Value> = from_value(r.context["attrs"].clone()).unwrap();
+    let pairwise_subject = oauth_opts.pairwise_subject(client_id, &subject);
 
-    let mut claims: HashMap<String, Value> = HashMap::new();
-    // The groups claim is added regardless of what scopes are requested.
-    claims.insert("groups".to_string(), attrs["groups"].clone());
+    let mut context: HashMap<String, Value> = HashMap::new();
+    context.insert("attrs".to_string(), json!(user.attrs));
 
-    for (attr_name, attr_value) in attrs {
-        let claim_name = match oauth_opts.attrs_map.get(&attr_name) {
-            Some(claim_name) => claim_name,
-            None => {
-                debug!("Skiping attribute '{}' not mapped to a claim", attr_name);
-                continue;
-            }
-        };
+    match hydra.timed("accept_login_request", |h| {
+        h.accept_login_request(
+            login_challenge.to_string(),
+            subject.clone(),
+            None,
+            Some(context),
+            pairwise_subject,
+            remember,
+            Some(oauth_opts.login_remember_for),
+        )
+    }) {
+        Ok(r) => {
+            record_login_attempt(LoginOutcome::Success);
+            audit.record(
+                audit_event(audit::Outcome::Success)
+                    .client_id(client_id)
+                    .subject(&subject),
+            );
+            info!(
+                "accepted login request with challenge `{}` for `{}`",
+                login_challenge, login
+            );
 
-        let claim_scope = match oauth_opts.claims_map.get(claim_name) {
-            Some(claim_scope) => claim_scope,
-            None => {
-                debug!("Skiping claim '{}' not mapped to a scope", claim_name);
-                continue;
+            match user.password_warning {
+                Some(warning) => {
+                    Response::Template(render_password_warning_template(warning, r.redirect_to))
+                }
+                None => Response::Redirect(Redirect::to(r.redirect_to)),
             }
-        };
-
-        if !r.requested_scope.contains(claim_scope) {
-            debug!(
-                "Skiping claim '{}' as client didn’t request scope '{}'",
-                claim_name, claim_scope
-            );
-            continue;
         }
-
-        debug!(
-            "Mapping attribute '{}' to claim '{}' for scope '{}' with value '{}'",
-            attr_name, claim_name, claim_scope, attr_value
-        );
-
-        claims.insert(claim_name.to_string(), attr_value);
-    }
-
-    match hydra.accept_consent_request(
-        consent_challenge,
-        r.requested_access_token_audience,
-        r.requested_scope,
-        Some(true),
-        Some(0), // Remember consent request indefinitely
-        Some(claims),
-    ) {
-        Ok(r) => Response::Redirect(Redirect::to(r.redirect_to)),
         Err(e) => {
-            warn!("unable to accept consent request: {}", e);
-            Response::Status(Status::InternalServerError)
+            record_login_attempt(LoginOutcome::HydraError);
+            warn!("unable to accept login request: {}", e);
+            reject_login(hydra, login_challenge, server_error())
         }
     }
 }
 
 #[get("/logout?<logout_challenge>")]
-fn logout(logout_challenge: String, hydra: State<Hydra>) -> Response {
+fn logout(
+    logout_challenge: String,
+    client: Client,
+    hydra: State<Hydra>,
+    audit: State<Audit>,
+) -> Response {
     if logout_challenge.is_empty() {
         return Response::Status(Status::NotFound);
     }
 
-    match hydra.accept_logout_request(logout_challenge.clone()) {
+    logger::set_field("challenge", logout_challenge.as_str());
+
+    // The subject is only needed for the audit log, the logout goes on
+    // without it.
+    let subject = match hydra.get_logout_request(logout_challenge.clone()) {
         Ok(r) => {
+            logger::set_field("subject", r.subject.as_str());
+            Some(r.subject)
+        }
+        Err(e) => {
+            warn!("unable to get logout request details: {}", e);
+            None
+        }
+    };
+
+    match hydra.timed("accept_logout_request", |h| {
+        h.accept_logout_request(logout_challenge.clone())
+    }) {
+        Ok(r) => {
+            logger::set_field("outcome", "success");
             info!(
                 "accepted logout request with challenge `{}`",
                 logout_challenge
             );
+            let mut event = audit::Event::new(audit::Kind::Logout, audit::Outcome::Success)
+                .source(client.ip, client.user_agent.as_deref())
+                .challenge(logout_challenge.as_str());
+            if let Some(subject) = subject.as_deref() {
+                event = event.subject(subject);
+            }
+            audit.record(event);
             Response::Redirect(Redirect::to(r.redirect_to))
         }
         Err(e) => {
-            warn!("unable to accept login request: {}", e);
+            logger::set_field("outcome", "hydra_error");
+            warn!("unable to accept logout request: {}", e);
             Response::Status(Status::InternalServerError)
         }
     }
 }
 
 #[get("/post-logout")]
-fn post_logout() -> Template {
+fn post_logout(mut cookies: Cookies, hydra: State<Hydra>) -> Response {
+    // Users logged out to switch accounts go back to the authorization
+    // request.
+    if let Some(redirect) = prompt::switch_account_redirect(&mut cookies, &hydra) {
+        return Response::Redirect(redirect);
+    }
+
     let context: HashMap<String, String> = HashMap::new();
-    Template::render("post-logout", &context)
+    Response::Template(Template::render("post-logout", &context))
 }
 
 #[get("/error?<error>&<error_description>&<error_hint>")]
@@ -425,6 +1041,25 @@ fn error(error: String, error_description: String, error_hint: String) -> Templa
     Template::render("error", &context)
 }
 
+// Metrics in the Prometheus text exposition format.
+#[get("/metrics")]
+fn get_metrics() -> Result<content::Content<String>, Status> {
+    let content_type = ContentType::with_params("text", "plain", ("version", "0.0.4"));
+
+    metrics::gather()
+        .map(|metrics| content::Content(content_type, metrics))
+        .map_err(|e| {
+            warn!("Unable to gather metrics: {}", e);
+            Status::InternalServerError
+        })
+}
+
+#[catch(403)]
+fn forbidden(_req: &Request) -> Template {
+    let context: HashMap<String, String> = HashMap::new();
+    Template::render("403", &context)
+}
+
 #[catch(404)]
 fn not_found(_req: &Request) -> Template {
     let context: HashMap<String, String> = HashMap::new();
@@ -436,3 +1071,44 @@ fn internal_server_error(_req: &Request) -> Template {
     let context: HashMap<String, String> = HashMap::new();
     Template::render("500", &context)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_real_ip_from_untrusted_peers() {
+        let remote: IpAddr = "192.0.2.1".parse().unwrap();
+        let real_ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert_eq!(client_ip(Some(remote), Some(real_ip), &[]), Some(remote));
+        assert_eq!(client_ip(None, Some(real_ip), &[remote]), None);
+    }
+
+    #[test]
+    fn uses_real_ip_from_trusted_proxies() {
+        let proxy: IpAddr = "192.0.2.1".parse().unwrap();
+        let real_ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert_eq!(
+            client_ip(Some(proxy), Some(real_ip), &[proxy]),
+            Some(real_ip)
+        );
+        assert_eq!(client_ip(Some(proxy), None, &[proxy]), Some(proxy));
+    }
+
+    #[test]
+    fn rejects_ldap_errors() {
+        let reject = ldap_rejection(&ldap::Error::PoolTimeout);
+        assert_eq!(reject.error, "temporarily_unavailable");
+        assert_eq!(reject.status_code, 503);
+
+        let reject = ldap_rejection(&ldap::Error::NoServerAvailable);
+        assert_eq!(reject.error, "temporarily_unavailable");
+        assert_eq!(reject.status_code, 503);
+
+        let reject = ldap_rejection(&ldap::Error::InvalidFilter("(".to_string()));
+        assert_eq!(reject.error, "server_error");
+        assert_eq!(reject.status_code, 500);
+    }
+}
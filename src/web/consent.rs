@@ -0,0 +1,507 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Consent screen: the user sees which client asks for what and allows or
+// denies it, possibly leaving out some of the scopes.
+
+use rocket::http::Status;
+use rocket::request::{Form, FormItems, FromForm, FromFormValue};
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_contrib::templates::Template;
+use serde_json::{from_value, json, Value};
+use std::collections::HashMap;
+use url::Url;
+
+use super::{csrf, server_error, Client, OauthOpts, Response};
+use crate::audit::{self, Audit};
+use crate::hydra::{ConsentRequest, Hydra, RejectRequest};
+use crate::logger;
+use crate::metrics;
+
+mod policy;
+
+use policy::Decision;
+pub use policy::Opts;
+
+// Scopes that can’t be left out when they are requested.
+const REQUIRED_SCOPES: &[&str] = &["openid"];
+
+// Claims released regardless of the granted scopes.
+const ALWAYS_RELEASED_CLAIMS: &[&str] = &["groups"];
+
+fn scope_description(scope: &str) -> Option<&'static str> {
+    match scope {
+        "openid" => Some("Sign you in with your account"),
+        "profile" => Some("Your name"),
+        "email" => Some("Your email address"),
+        "offline" | "offline_access" => {
+            Some("Keep access to your account while you aren’t using the application")
+        }
+        _ => None,
+    }
+}
+
+pub struct ConsentForm {
+    allow: bool,
+    scopes: Vec<String>,
+    remember: bool,
+    csrf_token: csrf::FormToken,
+}
+
+// Derived forms can’t hold repeated fields, there is one `scope` field per
+// scope left checked.
+impl<'f> FromForm<'f> for ConsentForm {
+    type Error = ();
+
+    fn from_form(items: &mut FormItems<'f>, strict: bool) -> Result<Self, Self::Error> {
+        let mut allow = None;
+        let mut scopes = vec![];
+        let mut remember = false;
+        let mut csrf_token = None;
+
+        for item in items {
+            match item.key.as_str() {
+                "action" => match item.value.as_str() {
+                    "allow" => allow = Some(true),
+                    "deny" => allow = Some(false),
+                    _ => return Err(()),
+                },
+                "scope" => scopes.push(item.value.url_decode().map_err(|_| ())?),
+                "remember" => remember = bool::from_form_value(item.value).map_err(|_| ())?,
+                "csrf_token" => {
+                    csrf_token = Some(csrf::FormToken::from_form_value(item.value).map_err(|_| ())?)
+                }
+                _ if strict => return Err(()),
+                _ => {}
+            }
+        }
+
+        Ok(ConsentForm {
+            allow: allow.ok_or(())?,
+            scopes,
+            remember,
+            csrf_token: csrf_token.or_else(csrf::FormToken::default).ok_or(())?,
+        })
+    }
+}
+
+// The user’s attributes, saved in the context when logging in.
+fn attrs(r: &ConsentRequest) -> Option<HashMap<String, Value>> {
+    match r.context.get("attrs") {
+        Some(attrs) => from_value(attrs.clone()).ok(),
+        None => None,
+    }
+}
+
+// The claims mapped from the user’s attributes, with the scope releasing
+// each of them.
+fn mapped_claims<'a>(
+    attrs: &'a HashMap<String, Value>,
+    oauth_opts: &'a OauthOpts,
+) -> Vec<(&'a str, &'a str, &'a Value)> {
+    let mut claims = vec![];
+
+    for (attr_name, attr_value) in attrs {
+        let claim_name = match oauth_opts.attrs_map.get(attr_name) {
+            Some(claim_name) => claim_name,
+            None => {
+                debug!("Skiping attribute '{}' not mapped to a claim", attr_name);
+                continue;
+            }
+        };
+
+        let claim_scope = match oauth_opts.claims_map.get(claim_name) {
+            Some(claim_scope) => claim_scope,
+            None => {
+                debug!("Skiping claim '{}' not mapped to a scope", claim_name);
+                continue;
+            }
+        };
+
+        claims.push((claim_name.as_str(), claim_scope.as_str(), attr_value));
+    }
+
+    claims
+}
+
+// The claims released when `scopes` are granted.
+fn claims(
+    attrs: &HashMap<String, Value>,
+    scopes: &[String],
+    oauth_opts: &OauthOpts,
+) -> HashMap<String, Value> {
+    let mut claims: HashMap<String, Value> = HashMap::new();
+
+    for claim_name in ALWAYS_RELEASED_CLAIMS {
+        if let Some(value) = attrs.get(*claim_name) {
+            claims.insert(claim_name.to_string(), value.clone());
+        }
+    }
+
+    for (claim_name, claim_scope, value) in mapped_claims(attrs, oauth_opts) {
+        if !scopes.iter().any(|scope| scope == claim_scope) {
+            debug!(
+                "Skiping claim '{}' as scope '{}' wasn’t granted",
+                claim_name, claim_scope
+            );
+            continue;
+        }
+
+        debug!(
+            "Mapping claim '{}' for scope '{}' with value '{}'",
+            claim_name, claim_scope, value
+        );
+
+        claims.insert(claim_name.to_string(), value.clone());
+    }
+
+    claims
+}
+
+// `uri` if it is an HTTP(S) URL, empty otherwise. Client URIs end up in links
+// and images, other schemes (e.g. `javascript:`) are never shown.
+fn web_uri(uri: &str) -> String {
+    match Url::parse(uri) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url.into_string(),
+        _ => String::new(),
+    }
+}
+
+fn render_template(
+    csrf_token: &str,
+    r: &ConsentRequest,
+    attrs: &HashMap<String, Value>,
+    oauth_opts: &OauthOpts,
+) -> Template {
+    let mapped_claims = mapped_claims(attrs, oauth_opts);
+
+    let scopes: Vec<Value> = r
+        .requested_scope
+        .iter()
+        .map(|scope| {
+            let mut claims: Vec<&str> = mapped_claims
+                .iter()
+                .filter(|(_, claim_scope, _)| *claim_scope == scope.as_str())
+                .map(|(claim_name, _, _)| *claim_name)
+                .collect();
+
+            let required = REQUIRED_SCOPES.contains(&scope.as_str());
+            if required {
+                claims.extend(
+                    ALWAYS_RELEASED_CLAIMS
+                        .iter()
+                        .filter(|c| attrs.contains_key(**c)),
+                );
+            }
+
+            claims.sort_unstable();
+
+            json!({
+                "name": scope,
+                "description": scope_description(scope),
+                "claims": claims,
+                "required": required,
+            })
+        })
+        .collect();
+
+    let client_name = match r.client.client_name.as_str() {
+        "" => r.client.client_id.as_str(),
+        client_name => client_name,
+    };
+
+    let context = json!({
+        "csrf_token": csrf_token,
+        "client": {
+            "name": client_name,
+            "uri": web_uri(&r.client.client_uri),
+            "logo_uri": web_uri(&r.client.logo_uri),
+            "policy_uri": web_uri(&r.client.policy_uri),
+            "tos_uri": web_uri(&r.client.tos_uri),
+        },
+        "scopes": scopes,
+    });
+
+    Template::render("consent", &context)
+}
+
+// A consent request being accepted, by the user or by the policy.
+struct Grant<'a> {
+    consent_challenge: String,
+    r: &'a ConsentRequest,
+    attrs: &'a HashMap<String, Value>,
+    scope: Vec<String>,
+    remember: bool,
+    // Why the user wasn’t asked, when they weren’t.
+    reason: Option<&'static str>,
+}
+
+fn accept(
+    grant: Grant,
+    client: &Client,
+    oauth_opts: &OauthOpts,
+    hydra: &Hydra,
+    audit: &Audit,
+) -> Response {
+    let Grant {
+        consent_challenge,
+        r,
+        attrs,
+        scope,
+        remember,
+        reason,
+    } = grant;
+
+    let claims = claims(attrs, &scope, oauth_opts);
+    let claim_names: Vec<String> = claims.keys().cloned().collect();
+    let audience = r.requested_access_token_audience.clone();
+
+    match hydra.timed("accept_consent_request", |h| {
+        h.accept_consent_request(
+            consent_challenge.clone(),
+            audience,
+            scope.clone(),
+            Some(remember),
+            Some(0), // Remember consent request indefinitely
+            Some(claims),
+        )
+    }) {
+        Ok(completed) => {
+            metrics::consent_accepted(r.client.client_id.as_str());
+            logger::set_field("outcome", "success");
+
+            let mut event = audit::Event::new(audit::Kind::Consent, audit::Outcome::Success)
+                .source(client.ip, client.user_agent.as_deref())
+                .subject(r.subject.as_str())
+                .client_id(r.client.client_id.as_str())
+                .scopes(&scope)
+                .claims(&claim_names);
+            if let Some(reason) = reason {
+                event = event.reason(reason);
+            }
+            audit.record(event);
+
+            Response::Redirect(Redirect::to(completed.redirect_to))
+        }
+        Err(e) => {
+            warn!("unable to accept consent request: {}", e);
+            reject(hydra, consent_challenge, server_error())
+        }
+    }
+}
+
+// Send the user back to the client with an OAuth 2.0 error.
+fn reject(hydra: &Hydra, consent_challenge: String, reject: RejectRequest) -> Response {
+    match hydra.reject_consent_request(consent_challenge.clone(), &reject) {
+        Ok(completed) => {
+            logger::set_field("outcome", reject.error.as_str());
+            info!(
+                "rejected consent request with challenge `{}`: {}",
+                consent_challenge, reject.error
+            );
+            Response::Redirect(Redirect::to(completed.redirect_to))
+        }
+        Err(e) => {
+            logger::set_field("outcome", "hydra_error");
+            warn!("unable to reject consent request: {}", e);
+            Response::Status(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/consent?<consent_challenge>")]
+pub fn consent(
+    consent_challenge: String,
+    csrf_token: csrf::Token,
+    client: Client,
+    opts: State<Opts>,
+    oauth_opts: State<OauthOpts>,
+    hydra: State<Hydra>,
+    audit: State<Audit>,
+) -> Response {
+    if consent_challenge.is_empty() {
+        return Response::Status(Status::NotFound);
+    }
+
+    logger::set_field("challenge", consent_challenge.as_str());
+
+    let r = match hydra.get_consent_request(consent_challenge.clone()) {
+        Ok(r) => r,
+        Err(e) => {
+            logger::set_field("outcome", "hydra_error");
+            warn!("Unable to get consent request details: {}", e);
+            return Response::Status(Status::InternalServerError);
+        }
+    };
+
+    logger::set_field("client_id", r.client.client_id.as_str());
+    logger::set_field("subject", r.subject.as_str());
+
+    let attrs = match attrs(&r) {
+        Some(attrs) => attrs,
+        None => {
+            warn!("Unable to get attrs from consent request’s context.");
+            return reject(
+                &hydra,
+                consent_challenge,
+                RejectRequest::new(
+                    "server_error",
+                    "The user’s attributes are missing from the login session.",
+                    500,
+                ),
+            );
+        }
+    };
+
+    if let Decision::Accept(reason) = opts.decide(&r) {
+        info!("Accepting consent request without asking: {}", reason);
+
+        let grant = Grant {
+            consent_challenge,
+            r: &r,
+            attrs: &attrs,
+            scope: r.requested_scope.clone(),
+            // Remembering it again would extend the previous consent, and
+            // trusted clients don’t need it.
+            remember: false,
+            reason: Some(reason),
+        };
+
+        return accept(grant, &client, &oauth_opts, &hydra, &audit);
+    }
+
+    Response::Template(render_template(
+        csrf_token.as_str(),
+        &r,
+        &attrs,
+        &oauth_opts,
+    ))
+}
+
+#[post("/consent?<consent_challenge>", data = "<form>")]
+pub fn post_consent(
+    consent_challenge: String,
+    form: Form<ConsentForm>,
+    csrf_token: csrf::Token,
+    client: Client,
+    oauth_opts: State<OauthOpts>,
+    hydra: State<Hydra>,
+    audit: State<Audit>,
+) -> Response {
+    if consent_challenge.is_empty() {
+        return Response::Status(Status::NotFound);
+    }
+
+    logger::set_field("challenge", consent_challenge.as_str());
+
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    // What was requested is never taken from the form.
+    let r = match hydra.get_consent_request(consent_challenge.clone()) {
+        Ok(r) => r,
+        Err(e) => {
+            logger::set_field("outcome", "hydra_error");
+            warn!("Unable to get consent request details: {}", e);
+            return Response::Status(Status::InternalServerError);
+        }
+    };
+
+    logger::set_field("client_id", r.client.client_id.as_str());
+    logger::set_field("subject", r.subject.as_str());
+
+    if !form.allow {
+        info!("Consent denied by {}", r.subject);
+
+        let response = reject(
+            &hydra,
+            consent_challenge,
+            RejectRequest::new(
+                "access_denied",
+                "The resource owner denied the request.",
+                403,
+            ),
+        );
+
+        // Only recorded once Hydra knows about it.
+        if let Response::Redirect(_) = response {
+            audit.record(
+                audit::Event::new(audit::Kind::Consent, audit::Outcome::Failure)
+                    .source(client.ip, client.user_agent.as_deref())
+                    .subject(r.subject.as_str())
+                    .client_id(r.client.client_id.as_str())
+                    .reason("denied"),
+            );
+        }
+
+        return response;
+    }
+
+    let attrs = match attrs(&r) {
+        Some(attrs) => attrs,
+        None => {
+            warn!("Unable to get attrs from consent request’s context.");
+            return reject(
+                &hydra,
+                consent_challenge,
+                RejectRequest::new(
+                    "server_error",
+                    "The user’s attributes are missing from the login session.",
+                    500,
+                ),
+            );
+        }
+    };
+
+    let grant = Grant {
+        consent_challenge,
+        r: &r,
+        attrs: &attrs,
+        scope: r
+            .requested_scope
+            .iter()
+            .filter(|scope| {
+                REQUIRED_SCOPES.contains(&scope.as_str()) || form.scopes.contains(*scope)
+            })
+            .cloned()
+            .collect(),
+        remember: form.remember,
+        reason: None,
+    };
+
+    accept(grant, &client, &oauth_opts, &hydra, &audit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_web_uris() {
+        assert_eq!(
+            web_uri("https://example.org/logo.png"),
+            "https://example.org/logo.png"
+        );
+        assert_eq!(web_uri(" http://example.org"), "http://example.org/");
+
+        assert_eq!(web_uri("javascript:alert(1)"), "");
+        assert_eq!(web_uri(" JavaScript:alert(1)"), "");
+        assert_eq!(web_uri("data:image/png;base64,AAAA"), "");
+        assert_eq!(web_uri("//example.org"), "");
+        assert_eq!(web_uri(""), "");
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Which consent requests are accepted without asking the user: those Hydra
+// says can be skipped (consent was given and remembered before) and those
+// of trusted, usually first-party, clients.
+
+use serde_json::Value;
+use structopt::StructOpt;
+
+use crate::hydra::ConsentRequest;
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "consent.trusted-clients",
+        long = "consent.trusted-clients",
+        env = "CONSENT_TRUSTED_CLIENTS",
+        hide_env_values = true,
+        value_name = "list",
+        use_delimiter = true,
+        help = "Comma separated list of client IDs that don’t need the user’s consent",
+        display_order = 66
+    )]
+    trusted_clients: Vec<String>,
+
+    #[structopt(
+        name = "consent.trusted-audiences",
+        long = "consent.trusted-audiences",
+        env = "CONSENT_TRUSTED_AUDIENCES",
+        hide_env_values = true,
+        value_name = "list",
+        use_delimiter = true,
+        help = "Comma separated list of audiences trusted clients can request without the user’s \
+                consent (any audience when empty)",
+        display_order = 67
+    )]
+    trusted_audiences: Vec<String>,
+
+    #[structopt(
+        name = "consent.trust-client-metadata",
+        long = "consent.trust-client-metadata",
+        env = "CONSENT_TRUST_CLIENT_METADATA",
+        hide_env_values = true,
+        value_name = "bool",
+        parse(try_from_str),
+        default_value = "false",
+        help = "Don’t ask for the consent of the user when the client’s metadata has `trusted` \
+                set to true (only enable if clients can’t set their own metadata)",
+        display_order = 68
+    )]
+    trust_client_metadata: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    // Accept without asking, for the given reason (a code of the audit
+    // log).
+    Accept(&'static str),
+    Prompt,
+}
+
+impl Opts {
+    pub fn check(&self) -> Result<(), String> {
+        if self.trusted_clients.iter().any(String::is_empty) {
+            return Err("empty client ID in trusted clients".to_string());
+        }
+
+        if self.trusted_audiences.iter().any(String::is_empty) {
+            return Err("empty audience in trusted audiences".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn decide(&self, r: &ConsentRequest) -> Decision {
+        if r.skip {
+            return Decision::Accept("consent_given");
+        }
+
+        let reason = if self.trusted_clients.contains(&r.client.client_id) {
+            "trusted_client"
+        } else if self.trust_client_metadata
+            && r.client.metadata.get("trusted") == Some(&Value::Bool(true))
+        {
+            "trusted_client_metadata"
+        } else {
+            return Decision::Prompt;
+        };
+
+        // Trusted clients still need the user’s consent for audiences that
+        // aren’t trusted, when some are.
+        if self.trusted_audiences.is_empty()
+            || r.requested_access_token_audience
+                .iter()
+                .all(|audience| self.trusted_audiences.contains(audience))
+        {
+            return Decision::Accept(reason);
+        }
+
+        Decision::Prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    fn opts() -> Opts {
+        Opts {
+            trusted_clients: vec!["first-party".to_string()],
+            trusted_audiences: vec!["api".to_string(), "other-api".to_string()],
+            trust_client_metadata: false,
+        }
+    }
+
+    fn request(client: Value, audiences: Value, skip: bool) -> ConsentRequest {
+        from_value(json!({
+            "client": client,
+            "requested_access_token_audience": audiences,
+            "skip": skip,
+            "subject": "jdoe",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_skipped_requests() {
+        let r = request(json!({"client_id": "third-party"}), json!([]), true);
+        assert_eq!(opts().decide(&r), Decision::Accept("consent_given"));
+    }
+
+    #[test]
+    fn accepts_trusted_clients() {
+        let r = request(json!({"client_id": "first-party"}), json!([]), false);
+        assert_eq!(opts().decide(&r), Decision::Accept("trusted_client"));
+
+        let r = request(json!({"client_id": "third-party"}), json!([]), false);
+        assert_eq!(opts().decide(&r), Decision::Prompt);
+    }
+
+    #[test]
+    fn accepts_clients_trusted_by_metadata_when_enabled() {
+        let client = json!({"client_id": "third-party", "metadata": {"trusted": true}});
+        let r = request(client, json!([]), false);
+        assert_eq!(opts().decide(&r), Decision::Prompt);
+
+        let mut opts = opts();
+        opts.trust_client_metadata = true;
+        assert_eq!(opts.decide(&r), Decision::Accept("trusted_client_metadata"));
+
+        let client = json!({"client_id": "third-party", "metadata": {"trusted": "true"}});
+        let r = request(client, json!([]), false);
+        assert_eq!(opts.decide(&r), Decision::Prompt);
+    }
+
+    #[test]
+    fn accepts_trusted_audiences() {
+        let third_party = json!({"client_id": "third-party"});
+        let first_party = json!({"client_id": "first-party"});
+
+        let r = request(third_party, json!(["api", "other-api"]), false);
+        assert_eq!(opts().decide(&r), Decision::Prompt);
+
+        let r = request(first_party.clone(), json!(["api", "other-api"]), false);
+        assert_eq!(opts().decide(&r), Decision::Accept("trusted_client"));
+
+        let r = request(first_party.clone(), json!(["api", "untrusted-api"]), false);
+        assert_eq!(opts().decide(&r), Decision::Prompt);
+
+        let mut opts = opts();
+        opts.trusted_audiences.clear();
+        let r = request(first_party, json!(["untrusted-api"]), false);
+        assert_eq!(opts.decide(&r), Decision::Accept("trusted_client"));
+    }
+
+    #[test]
+    fn rejects_empty_entries() {
+        assert!(opts().check().is_ok());
+
+        let mut opts = opts();
+        opts.trusted_audiences.push(String::new());
+        assert!(opts.check().is_err());
+    }
+}
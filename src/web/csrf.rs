@@ -0,0 +1,253 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Cross-site request forgery protection with signed double-submit tokens: the
+// same token is set in a cookie and in a hidden field of every form, and
+// forms are only accepted when both match. Tokens are signed so that only
+// tokens issued by the server are accepted, but they aren’t bound to a
+// session: anyone able to set cookies for this domain (e.g. from a sibling
+// subdomain) can plant a token of their own along with a matching form, so
+// the server shouldn’t share its domain with untrusted hosts.
+
+use hmac::{Hmac, Mac};
+use rocket::http::{Cookie, RawStr, SameSite, Status};
+use rocket::request::{self, FromFormValue, FromRequest, Request};
+use rocket::{Outcome, State};
+use sha2::Sha256;
+use std::str::Utf8Error;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+const COOKIE_NAME: &str = "csrf_token";
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "csrf.secret",
+        long = "csrf.secret",
+        env = "CSRF_SECRET",
+        hide_env_values = true,
+        value_name = "string",
+        help = "Secret used to sign CSRF tokens, shared by all instances [default: random, forms \
+                being invalidated on restart]",
+        display_order = 105
+    )]
+    secret: Option<String>,
+
+    #[structopt(
+        name = "csrf.secure-cookie",
+        long = "csrf.secure-cookie",
+        env = "CSRF_SECURE_COOKIE",
+        hide_env_values = true,
+        value_name = "bool",
+        parse(try_from_str),
+        help = "Only send the CSRF cookie over HTTPS, to enable behind a reverse proxy \
+                terminating TLS [default: true when TLS is enabled]",
+        display_order = 106
+    )]
+    secure_cookie: Option<bool>,
+}
+
+pub struct Csrf {
+    secret: Vec<u8>,
+    cookie_path: String,
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new(opts: Opts, cookie_path: &str, tls: bool) -> Csrf {
+        let secret = match opts.secret {
+            Some(secret) => secret.into_bytes(),
+            None => format!("{}{}", Uuid::new_v4(), Uuid::new_v4()).into_bytes(),
+        };
+
+        Csrf {
+            secret,
+            cookie_path: cookie_path.to_string(),
+            secure: opts.secure_cookie.unwrap_or(tls),
+        }
+    }
+
+    fn sign(&self, nonce: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts any key size");
+        mac.input(nonce.as_bytes());
+
+        mac
+    }
+
+    // Tokens are made of a random nonce and its signature, separated by a
+    // dot.
+    fn generate(&self) -> String {
+        let nonce = Uuid::new_v4().to_simple().to_string();
+        let signature = self.sign(nonce.as_str()).result().code();
+
+        format!(
+            "{}.{}",
+            nonce,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn verify(&self, token: &str) -> bool {
+        let mut parts = token.splitn(2, '.');
+
+        let (nonce, signature) = match (parts.next(), parts.next()) {
+            (Some(nonce), Some(signature)) => (nonce, signature),
+            _ => return false,
+        };
+
+        match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => self.sign(nonce).verify(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+// The client’s token, taken from its cookie or generated, and then set in a
+// cookie, when it doesn’t have a valid one.
+pub struct Token(String);
+
+impl Token {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    // Whether the token submitted with a form matches the cookie.
+    pub fn check(&self, field: &FormToken) -> bool {
+        let matches = self.0.len() == field.0.len()
+            && self
+                .0
+                .bytes()
+                .zip(field.0.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+
+        if !matches {
+            info!("Refusing form submission with an invalid CSRF token");
+        }
+
+        matches
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Token {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let csrf = match request.guard::<State<Csrf>>() {
+            Outcome::Success(csrf) => csrf,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let mut cookies = request.cookies();
+
+        if let Some(cookie) = cookies.get(COOKIE_NAME) {
+            if csrf.verify(cookie.value()) {
+                return Outcome::Success(Token(cookie.value().to_string()));
+            }
+        }
+
+        let token = csrf.generate();
+
+        cookies.add(
+            Cookie::build(COOKIE_NAME, token.clone())
+                .path(csrf.cookie_path.clone())
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .secure(csrf.secure)
+                .finish(),
+        );
+
+        Outcome::Success(Token(token))
+    }
+}
+
+// The `csrf_token` field of a form, empty when missing so that forged
+// requests are refused like any other mismatch.
+pub struct FormToken(String);
+
+impl<'v> FromFormValue<'v> for FormToken {
+    type Error = Utf8Error;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        value.url_decode().map(FormToken)
+    }
+
+    fn default() -> Option<Self> {
+        Some(FormToken(String::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_csrf(secret: &str) -> Csrf {
+        Csrf::new(
+            Opts {
+                secret: Some(secret.to_string()),
+                secure_cookie: None,
+            },
+            "/",
+            false,
+        )
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let csrf = new_csrf("secret");
+        let token = csrf.generate();
+
+        assert!(csrf.verify(token.as_str()));
+        assert_ne!(token, csrf.generate());
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let csrf = new_csrf("secret");
+        let token = csrf.generate();
+        let (nonce, signature) = token.split_at(token.find('.').unwrap());
+
+        assert!(!new_csrf("other").verify(token.as_str()));
+        assert!(!csrf.verify(format!("{}0{}", nonce, signature).as_str()));
+        assert!(!csrf.verify(format!("{}.", nonce).as_str()));
+        assert!(!csrf.verify(format!("{}.!!!", nonce).as_str()));
+        assert!(!csrf.verify(nonce));
+        assert!(!csrf.verify(""));
+    }
+
+    #[test]
+    fn checks_form_tokens() {
+        let token = Token("abc.def".to_string());
+
+        assert!(token.check(&FormToken("abc.def".to_string())));
+        assert!(!token.check(&FormToken("abc.deg".to_string())));
+        assert!(!token.check(&FormToken("abc.de".to_string())));
+        assert!(!token.check(&FormToken(String::new())));
+    }
+
+    #[test]
+    fn secures_cookies() {
+        let opts = |secure_cookie| Opts {
+            secret: None,
+            secure_cookie,
+        };
+
+        assert!(!Csrf::new(opts(None), "/", false).secure);
+        assert!(Csrf::new(opts(None), "/", true).secure);
+        assert!(Csrf::new(opts(Some(true)), "/", false).secure);
+        assert!(!Csrf::new(opts(Some(false)), "/", true).secure);
+    }
+}
@@ -1,4 +1,138 @@
 use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+use crate::hydra::Hydra;
+use crate::ldap::LDAP;
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "health.cache-ttl",
+        long = "health.cache-ttl",
+        env = "HEALTH_CACHE_TTL",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "10",
+        help = "Time in seconds the result of readiness checks is cached for",
+        display_order = 100
+    )]
+    cache_ttl: u64,
+
+    #[structopt(
+        name = "health.timeout",
+        long = "health.timeout",
+        env = "HEALTH_TIMEOUT",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "5",
+        help = "Timeout in seconds for the Hydra readiness check",
+        display_order = 101
+    )]
+    timeout: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    status: &'static str,
+    latency_ms: u64,
+    // Only logged, the errors tell about the servers behind.
+    #[serde(skip)]
+    error: Option<String>,
+}
+
+impl Check {
+    fn run<E: Display>(f: impl FnOnce() -> Result<(), E>) -> Check {
+        let start = Instant::now();
+        let result = f();
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        match result {
+            Ok(()) => Check {
+                status: "up",
+                latency_ms,
+                error: None,
+            },
+            Err(e) => Check {
+                status: "down",
+                latency_ms,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+// Both LDAP and Hydra are required to log anyone in. Results are cached so
+// frequent probes don’t turn into a bind on the LDAP server each time.
+pub struct Readiness {
+    cache_ttl: Duration,
+    timeout: Duration,
+    cache: Mutex<Option<(Instant, Report)>>,
+}
+
+impl Readiness {
+    pub fn new(opts: Opts) -> Readiness {
+        Readiness {
+            cache_ttl: Duration::from_secs(opts.cache_ttl),
+            timeout: Duration::from_secs(opts.timeout),
+            cache: Mutex::new(None),
+        }
+    }
+
+    fn report(&self, hydra: &Hydra, ldap: &LDAP) -> Report {
+        // The lock isn’t held during the checks so a slow server doesn’t
+        // block every probe.
+        let cached = self.cache.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+        if let Some((checked_at, report)) = cached {
+            if checked_at.elapsed() < self.cache_ttl {
+                return report;
+            }
+        }
+
+        let mut checks = BTreeMap::new();
+        checks.insert("ldap", Check::run(|| ldap.check()));
+        checks.insert("hydra", Check::run(|| hydra.check(self.timeout)));
+
+        for (name, check) in checks.iter().filter(|(_, check)| !check.is_up()) {
+            warn!(
+                "Readiness check `{}` failed: {}",
+                name,
+                check.error.as_deref().unwrap_or_default()
+            );
+        }
+
+        let report = Report {
+            status: match checks.values().all(Check::is_up) {
+                true => "up",
+                false => "down",
+            },
+            checks,
+        };
+
+        *self.cache.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((Instant::now(), report.clone()));
+
+        report
+    }
+}
 
 #[get("/live")]
 pub fn live() -> Status {
@@ -6,6 +140,17 @@ pub fn live() -> Status {
 }
 
 #[get("/ready")]
-pub fn ready() -> Status {
-    Status::Ok
+pub fn ready(
+    readiness: State<Readiness>,
+    hydra: State<Hydra>,
+    ldap: State<LDAP>,
+) -> status::Custom<Json<Report>> {
+    let report = readiness.report(&hydra, &ldap);
+
+    let status = match report.status {
+        "up" => Status::Ok,
+        _ => Status::ServiceUnavailable,
+    };
+
+    status::Custom(status, Json(report))
 }
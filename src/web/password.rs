@@ -0,0 +1,292 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::State;
+use rocket_contrib::templates::Template;
+use std::collections::HashMap;
+use structopt::StructOpt;
+
+use super::reset::Reset;
+use super::throttle::Throttle;
+use super::{
+    csrf, ldap_rejection, login_user, record_failure, reject_login, Client, LoginAttempt,
+    OauthOpts, Response,
+};
+use crate::audit::{self, Audit};
+use crate::hydra::Hydra;
+use crate::ldap::{self, LDAP};
+
+// Local password policy, checked before sending the new password to the LDAP
+// server (which may enforce its own policy).
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "password.min-length",
+        long = "password.min-length",
+        env = "PASSWORD_MIN_LENGTH",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "8",
+        help = "Minimum length of new passwords",
+        display_order = 70
+    )]
+    min_length: usize,
+
+    #[structopt(
+        name = "password.min-classes",
+        long = "password.min-classes",
+        env = "PASSWORD_MIN_CLASSES",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "1",
+        help = "Minimum number of character classes (lowercase letters, uppercase letters, \
+                digits and others) in new passwords",
+        display_order = 71
+    )]
+    min_classes: usize,
+}
+
+impl Opts {
+    // Why the new password is rejected by the local policy, if it is.
+    pub fn check(
+        &self,
+        login: &str,
+        current_password: Option<&str>,
+        new_password: &str,
+        new_password_confirm: &str,
+    ) -> Option<String> {
+        if new_password != new_password_confirm {
+            return Some("The new passwords don’t match.".to_string());
+        }
+
+        if Some(new_password) == current_password {
+            return Some("The new password must be different from the current one.".to_string());
+        }
+
+        if new_password.chars().count() < self.min_length {
+            return Some(format!(
+                "The new password must be at least {} characters long.",
+                self.min_length
+            ));
+        }
+
+        let classes = [
+            new_password.chars().any(|c| c.is_lowercase()),
+            new_password.chars().any(|c| c.is_uppercase()),
+            new_password.chars().any(|c| c.is_numeric()),
+            new_password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+
+        if classes.iter().filter(|c| **c).count() < self.min_classes {
+            return Some(format!(
+                "The new password must contain at least {} of: lowercase letters, uppercase \
+                 letters, digits and other characters.",
+                self.min_classes
+            ));
+        }
+
+        if !login.is_empty() && new_password.to_lowercase().contains(&login.to_lowercase()) {
+            return Some("The new password must not contain your login.".to_string());
+        }
+
+        None
+    }
+}
+
+#[derive(FromForm)]
+pub struct PasswordForm {
+    login: String,
+    current_password: String,
+    new_password: String,
+    new_password_confirm: String,
+    remember: Option<bool>,
+    csrf_token: csrf::FormToken,
+}
+
+// When `login_challenge` is set, the user is in the middle of logging in and
+// is logged in once the password is changed.
+pub fn render_template(
+    csrf_token: &str,
+    login: &str,
+    login_challenge: Option<&str>,
+    remember: Option<bool>,
+    form_error: Option<String>,
+    form_success: Option<String>,
+) -> Template {
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+    context.insert("login".to_string(), login.to_string());
+
+    if let Some(login_challenge) = login_challenge {
+        context.insert("login_challenge".to_string(), login_challenge.to_string());
+    }
+
+    if let Some(true) = remember {
+        context.insert("remember".to_string(), "true".to_string());
+    }
+
+    if let Some(form_error) = form_error {
+        context.insert("form_error".to_string(), form_error);
+    }
+
+    if let Some(form_success) = form_success {
+        context.insert("form_success".to_string(), form_success);
+    }
+
+    Template::render("password", &context)
+}
+
+fn error_message(e: &ldap::Error) -> Option<String> {
+    match e {
+        ldap::Error::UserNotFound(_) | ldap::Error::InvalidCredentials => {
+            Some("Invalid login or password.".to_string())
+        }
+        ldap::Error::PasswordRejected(reason) => {
+            Some(format!("The new password was rejected: {}.", reason))
+        }
+        ldap::Error::LoginRestricted
+        | ldap::Error::AccountDisabled
+        | ldap::Error::AccountExpired
+        | ldap::Error::AccountLocked
+        | ldap::Error::PasswordExpired
+        | ldap::Error::PasswordMustChange
+        | ldap::Error::PasswordMustReset => Some(
+            "Your password can’t be changed here, please contact the site administrator."
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+#[get("/password?<login_challenge>")]
+pub fn password(login_challenge: Option<String>, csrf_token: csrf::Token) -> Template {
+    render_template(
+        csrf_token.as_str(),
+        "",
+        login_challenge.as_deref(),
+        None,
+        None,
+        None,
+    )
+}
+
+#[post("/password?<login_challenge>", data = "<form>")]
+pub fn post_password(
+    login_challenge: Option<String>,
+    form: Form<PasswordForm>,
+    csrf_token: csrf::Token,
+    client: Client,
+    opts: State<Opts>,
+    oauth_opts: State<OauthOpts>,
+    hydra: State<Hydra>,
+    ldap: State<LDAP>,
+    throttle: State<Throttle>,
+    audit: State<Audit>,
+    reset: State<Reset>,
+) -> Response {
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    let login_challenge = login_challenge.filter(|c| !c.is_empty());
+    let render = |form_error: Option<String>, form_success: Option<String>| {
+        Response::Template(render_template(
+            csrf_token.as_str(),
+            form.login.as_str(),
+            login_challenge.as_deref(),
+            form.remember,
+            form_error,
+            form_success,
+        ))
+    };
+
+    if let Some(message) = opts.check(
+        form.login.as_str(),
+        Some(form.current_password.as_str()),
+        form.new_password.as_str(),
+        form.new_password_confirm.as_str(),
+    ) {
+        return render(Some(message), None);
+    }
+
+    let audit_event = |outcome: audit::Outcome| {
+        audit::Event::new(audit::Kind::PasswordChange, outcome)
+            .source(client.ip, client.user_agent.as_deref())
+            .login(form.login.as_str())
+    };
+
+    // The current password can be guessed here as well as on the login page.
+    if let Some(delay) = throttle.check(form.login.as_str(), client.ip) {
+        info!(
+            "Refusing password change for {} from {}: throttled for {}s",
+            form.login, client.ip, delay
+        );
+        audit.record(audit_event(audit::Outcome::Failure).reason("throttled"));
+        return render(
+            Some("Too many failed attempts, please try again later.".to_string()),
+            None,
+        );
+    }
+
+    if let Err(e) = ldap.change_password(
+        form.login.as_str(),
+        form.current_password.as_str(),
+        form.new_password.as_str(),
+    ) {
+        audit.record(audit_event(audit::Outcome::Failure).reason(e.reason()));
+
+        return match error_message(&e) {
+            Some(message) => {
+                if let ldap::Error::UserNotFound(_) | ldap::Error::InvalidCredentials = e {
+                    record_failure(&throttle, &audit, form.login.as_str(), &client);
+                }
+
+                info!("Unable to change password of {}: {}", form.login, e);
+                render(Some(message), None)
+            }
+            None => {
+                warn!("LDAP Error: {}", e);
+
+                match &login_challenge {
+                    Some(login_challenge) => {
+                        reject_login(&hydra, login_challenge, ldap_rejection(&e))
+                    }
+                    None => Response::Status(Status::InternalServerError),
+                }
+            }
+        };
+    }
+
+    audit.record(audit_event(audit::Outcome::Success));
+
+    match &login_challenge {
+        Some(login_challenge) => {
+            let attempt = LoginAttempt {
+                login_challenge,
+                login: form.login.as_str(),
+                password: form.new_password.as_str(),
+                remember: form.remember,
+                client: &client,
+                csrf_token: &csrf_token,
+                reset_enabled: reset.enabled(),
+            };
+
+            login_user(attempt, &oauth_opts, &hydra, &ldap, &throttle, &audit)
+        }
+        None => render(None, Some("Your password has been changed.".to_string())),
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// OpenID Connect `prompt` parameter of the authorization request, read from
+// its URL as Hydra doesn’t expose it, and the account selection page shown
+// for `prompt=select_account`. Hydra handles `prompt=login` and `max_age`
+// itself, by not skipping the login.
+
+use rocket::http::{Cookie, Cookies, SameSite, Status};
+use rocket::request::Form;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_contrib::templates::Template;
+use std::collections::HashMap;
+use url::{form_urlencoded, Url};
+
+use super::{accept_session, csrf, reject_login, server_error, Client, OauthOpts, Response};
+use crate::audit::{self, Audit};
+use crate::hydra::{Hydra, LoginRequest};
+use crate::logger;
+
+// The authorization request to go back to once logged out to switch
+// accounts.
+const SWITCH_ACCOUNT_COOKIE: &str = "switch_account";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Prompt {
+    pub none: bool,
+    pub select_account: bool,
+}
+
+impl Prompt {
+    pub fn from_request(r: &LoginRequest) -> Prompt {
+        let url = match Url::parse(r.request_url.as_str()) {
+            Ok(url) => url,
+            Err(_) => return Prompt::default(),
+        };
+
+        let mut prompt = Prompt::default();
+
+        for (key, value) in url.query_pairs() {
+            if key != "prompt" {
+                continue;
+            }
+
+            for value in value.split_whitespace() {
+                match value {
+                    "none" => prompt.none = true,
+                    "select_account" => prompt.select_account = true,
+                    _ => {}
+                }
+            }
+        }
+
+        prompt
+    }
+}
+
+pub fn render_select_account_template(
+    csrf_token: &str,
+    login_challenge: &str,
+    subject: &str,
+    switch_enabled: bool,
+) -> Template {
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+    context.insert("login_challenge".to_string(), login_challenge.to_string());
+    context.insert("subject".to_string(), subject.to_string());
+
+    if switch_enabled {
+        context.insert("switch_enabled".to_string(), "true".to_string());
+    }
+
+    Template::render("select-account", &context)
+}
+
+// Where to go after logging out, when it was to switch accounts.
+pub fn switch_account_redirect(cookies: &mut Cookies, hydra: &Hydra) -> Option<Redirect> {
+    let url = cookies.get(SWITCH_ACCOUNT_COOKIE)?.value().parse::<Url>();
+    cookies.remove(Cookie::named(SWITCH_ACCOUNT_COOKIE));
+
+    // The cookie could have been set by someone else, it must lead back to
+    // Hydra.
+    match url {
+        Ok(url) if hydra.is_public_url(&url) => Some(Redirect::to(url.into_string())),
+        _ => None,
+    }
+}
+
+#[derive(FromForm)]
+pub struct SelectAccountForm {
+    action: String,
+    csrf_token: csrf::FormToken,
+}
+
+#[post("/select-account?<login_challenge>", data = "<form>")]
+pub fn post_select_account(
+    login_challenge: String,
+    form: Form<SelectAccountForm>,
+    csrf_token: csrf::Token,
+    client: Client,
+    mut cookies: Cookies,
+    oauth_opts: State<OauthOpts>,
+    hydra: State<Hydra>,
+    audit: State<Audit>,
+) -> Response {
+    if login_challenge.is_empty() {
+        return Response::Status(Status::NotFound);
+    }
+
+    logger::set_field("challenge", login_challenge.as_str());
+
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    let r = match hydra.get_login_request(login_challenge.clone()) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("unable to get login request details: {}", e);
+            return Response::Status(Status::InternalServerError);
+        }
+    };
+
+    logger::set_field("client_id", r.client.client_id.as_str());
+
+    // The session ended in the meantime.
+    if !r.skip {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("login_challenge", login_challenge.as_str())
+            .finish();
+
+        return Response::Redirect(Redirect::to(format!("login?{}", query)));
+    }
+
+    logger::set_field("subject", r.subject.as_str());
+
+    match form.action.as_str() {
+        "continue" => accept_session(login_challenge, r, &oauth_opts, &hydra),
+        // The login request can only be accepted for the current user, the
+        // browser is logged out through Hydra and the authorization request
+        // starts over once the session is gone. Only this browser’s session
+        // ends.
+        "switch" => {
+            let logout_url = match hydra.browser_logout_url() {
+                Some(logout_url) => logout_url,
+                None => return Response::Status(Status::BadRequest),
+            };
+
+            if r.request_url.is_empty() {
+                return reject_login(&hydra, login_challenge.as_str(), server_error());
+            }
+
+            cookies.add(
+                Cookie::build(SWITCH_ACCOUNT_COOKIE, r.request_url.clone())
+                    .http_only(true)
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            );
+
+            info!("Logging {} out to switch account", r.subject);
+            audit.record(
+                audit::Event::new(audit::Kind::Logout, audit::Outcome::Success)
+                    .source(client.ip, client.user_agent.as_deref())
+                    .subject(r.subject.as_str())
+                    .client_id(r.client.client_id.as_str())
+                    .challenge(login_challenge.as_str())
+                    .reason("switch_account"),
+            );
+
+            Response::Redirect(Redirect::to(logout_url.into_string()))
+        }
+        _ => Response::Status(Status::BadRequest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    fn prompt(request_url: &str) -> Prompt {
+        let r: LoginRequest = from_value(json!({
+            "request_url": request_url,
+            "skip": false,
+            "subject": "",
+        }))
+        .unwrap();
+
+        Prompt::from_request(&r)
+    }
+
+    #[test]
+    fn parses_prompt() {
+        let url = "https://example.org/oauth2/auth?client_id=app&prompt=";
+
+        assert_eq!(
+            prompt(&format!("{}none", url)),
+            Prompt {
+                none: true,
+                select_account: false
+            }
+        );
+        assert_eq!(
+            prompt(&format!("{}select_account", url)),
+            Prompt {
+                none: false,
+                select_account: true
+            }
+        );
+        assert_eq!(
+            prompt(&format!("{}login+select_account%20none", url)),
+            Prompt {
+                none: true,
+                select_account: true
+            }
+        );
+        assert_eq!(prompt(&format!("{}consent", url)), Prompt::default());
+    }
+
+    #[test]
+    fn ignores_other_parameters() {
+        assert_eq!(
+            prompt("https://example.org/oauth2/auth?state=none&max_age=0"),
+            Prompt::default()
+        );
+        assert_eq!(prompt("https://example.org/oauth2/auth"), Prompt::default());
+        assert_eq!(prompt(""), Prompt::default());
+        assert_eq!(prompt("not a URL"), Prompt::default());
+    }
+}
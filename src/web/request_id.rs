@@ -0,0 +1,71 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Correlation ID attached to every line logged while handling a request and
+// returned in the response. The ID sent by a reverse proxy in `X-Request-ID`
+// is reused when there is one.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use uuid::Uuid;
+
+use crate::logger;
+
+const HEADER: &str = "X-Request-ID";
+const MAX_LENGTH: usize = 128;
+
+struct Id(String);
+
+pub struct RequestId;
+
+impl RequestId {
+    fn id<'r>(request: &'r Request) -> &'r Id {
+        request.local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(HEADER)
+                .filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= MAX_LENGTH
+                        && id.chars().all(|c| c.is_ascii_graphic())
+                })
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+            Id(id)
+        })
+    }
+}
+
+impl Fairing for RequestId {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    // Requests are handled on a single thread, from the request to the
+    // response callbacks.
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        logger::clear_fields();
+        logger::set_field("request_id", Self::id(request).0.as_str());
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        response.set_raw_header(HEADER, Self::id(request).0.clone());
+        logger::clear_fields();
+    }
+}
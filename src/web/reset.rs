@@ -0,0 +1,831 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Forgotten password reset: a signed token is emailed to the user, the link
+// it is part of leads to a form setting a new password with the service
+// account. Tokens carry a digest of attributes that change along with the
+// password, so they can only be used once even across restarts and
+// instances.
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::State;
+use rocket_contrib::templates::Template;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use thiserror::Error;
+use url::Url;
+
+use super::{csrf, password, Client, Response};
+use crate::audit::{self, Audit};
+use crate::ldap::{self, LDAP};
+use crate::mail::{self, Mailer};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(
+        name = "reset.secret",
+        long = "reset.secret",
+        env = "RESET_SECRET",
+        hide_env_values = true,
+        value_name = "string",
+        requires = "reset.url",
+        help = "Secret used to sign password reset tokens (enables password reset)",
+        display_order = 72
+    )]
+    secret: Option<String>,
+
+    #[structopt(
+        name = "reset.url",
+        long = "reset.url",
+        env = "RESET_URL",
+        hide_env_values = true,
+        value_name = "url",
+        help = "Public URL of the web server, used to build the links sent by email",
+        display_order = 73
+    )]
+    url: Option<Url>,
+
+    #[structopt(
+        name = "reset.token-lifetime",
+        long = "reset.token-lifetime",
+        env = "RESET_TOKEN_LIFETIME",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "3600",
+        help = "Time in seconds password reset links are valid for",
+        display_order = 74
+    )]
+    token_lifetime: u64,
+
+    #[structopt(
+        name = "reset.mail-attribute",
+        long = "reset.mail-attribute",
+        env = "RESET_MAIL_ATTRIBUTE",
+        hide_env_values = true,
+        value_name = "string",
+        default_value = "mail",
+        help = "LDAP attribute holding the address password reset links are sent to",
+        display_order = 75
+    )]
+    mail_attribute: String,
+
+    #[structopt(
+        name = "reset.rate-limit-window",
+        long = "reset.rate-limit-window",
+        env = "RESET_RATE_LIMIT_WINDOW",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "900",
+        help = "Time window in seconds over which password reset requests are limited",
+        display_order = 76
+    )]
+    rate_limit_window: u64,
+
+    #[structopt(
+        name = "reset.rate-limit-per-login",
+        long = "reset.rate-limit-per-login",
+        env = "RESET_RATE_LIMIT_PER_LOGIN",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "3",
+        help = "Maximum number of password reset requests for the same login in the time window",
+        display_order = 77
+    )]
+    rate_limit_per_login: usize,
+
+    #[structopt(
+        name = "reset.rate-limit-per-ip",
+        long = "reset.rate-limit-per-ip",
+        env = "RESET_RATE_LIMIT_PER_IP",
+        hide_env_values = true,
+        value_name = "integer",
+        default_value = "10",
+        help = "Maximum number of password reset requests from the same IP address in the time \
+                window",
+        display_order = 78
+    )]
+    rate_limit_per_ip: usize,
+
+    #[structopt(
+        name = "reset.password-state-attributes",
+        long = "reset.password-state-attributes",
+        env = "RESET_PASSWORD_STATE_ATTRIBUTES",
+        hide_env_values = true,
+        value_name = "list",
+        use_delimiter = true,
+        default_value = "pwdChangedTime,pwdLastSet,userPassword",
+        help = "Comma separated list of LDAP attributes changing along with the user’s password, \
+                reset links being invalidated once one of them changes (at least one of them \
+                must be readable by the bind DN for links to be single use across restarts and \
+                instances)",
+        display_order = 79
+    )]
+    password_state_attributes: Vec<String>,
+}
+
+impl Opts {
+    pub fn check(&self) -> Result<(), String> {
+        if self.secret.as_deref() == Some("") {
+            return Err("the secret can’t be empty".to_string());
+        }
+
+        if let Some(url) = &self.url {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(format!("not an HTTP URL: {}", url));
+            }
+
+            // Links are relative to the URL.
+            if !url.path().ends_with('/') {
+                return Err(format!("the URL must end with `/`: {}", url));
+            }
+        }
+
+        if self.token_lifetime == 0 {
+            return Err("the token lifetime can’t be 0".to_string());
+        }
+
+        if self.mail_attribute.is_empty() {
+            return Err("the mail attribute can’t be empty".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+enum SendError {
+    #[error(transparent)]
+    LdapError(#[from] ldap::Error),
+
+    #[error("user has no `{0}` attribute")]
+    MissingMail(String),
+
+    #[error("unable to build reset link")]
+    InvalidLink,
+
+    #[error(transparent)]
+    MailError(#[from] mail::Error),
+}
+
+impl SendError {
+    // Code of the error in the audit log.
+    fn reason(&self) -> &'static str {
+        match self {
+            SendError::LdapError(e) => e.reason(),
+            SendError::MissingMail(_) => "missing_mail",
+            SendError::InvalidLink => "invalid_link",
+            SendError::MailError(_) => "mail_error",
+        }
+    }
+}
+
+pub struct Reset {
+    opts: Opts,
+    // Requests made in the current time window, by login and by IP address.
+    requests: Mutex<HashMap<String, Vec<Instant>>>,
+    // Tokens being or already used on this instance, with their expiration
+    // timestamp. This prevents concurrent uses of the same token, the
+    // password state preventing later ones.
+    used_tokens: Mutex<HashMap<String, i64>>,
+}
+
+// A valid token.
+#[derive(Debug, PartialEq)]
+struct Claims {
+    login: String,
+    expires: i64,
+    // Digest of the password state attributes when the token was issued.
+    state: String,
+}
+
+impl Reset {
+    pub fn new(opts: Opts) -> Reset {
+        Reset {
+            opts,
+            requests: Mutex::new(HashMap::new()),
+            used_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.opts.secret.is_some()
+    }
+
+    // Record a request for `login` from `ip`, returns false without
+    // recording it if either one reached its limit.
+    fn allow(&self, login: &str, ip: IpAddr) -> bool {
+        let window = Duration::from_secs(self.opts.rate_limit_window);
+        let now = Instant::now();
+
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        requests.retain(|_, instants| {
+            instants.retain(|instant| now.duration_since(*instant) < window);
+            !instants.is_empty()
+        });
+
+        let keys = [
+            (
+                format!("login:{}", login.to_lowercase()),
+                self.opts.rate_limit_per_login,
+            ),
+            (format!("ip:{}", ip), self.opts.rate_limit_per_ip),
+        ];
+
+        // Refused requests aren’t recorded, clients retrying steadily would
+        // otherwise never get out of the window.
+        let allowed = keys.iter().all(|(key, limit)| {
+            requests
+                .get(key)
+                .map_or(true, |instants| instants.len() < *limit)
+        });
+
+        if allowed {
+            for (key, _) in keys.iter() {
+                requests
+                    .entry(key.clone())
+                    .or_insert_with(Vec::new)
+                    .push(now);
+            }
+        }
+
+        allowed
+    }
+
+    fn sign(&self, payload: &str) -> Option<Hmac<Sha256>> {
+        let secret = self.opts.secret.as_ref()?;
+
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key size");
+        mac.input(payload.as_bytes());
+
+        Some(mac)
+    }
+
+    // Digest of the user’s password state attributes, their values are not
+    // put in links as is.
+    fn password_state(&self, attrs: &HashMap<String, Value>) -> Option<String> {
+        let values: Vec<String> = self
+            .opts
+            .password_state_attributes
+            .iter()
+            .filter_map(|name| {
+                attrs
+                    .iter()
+                    .find(|(attr, _)| attr.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| format!("{}={}", name, value))
+            })
+            .collect();
+
+        let signature = self
+            .sign(format!("password-state\0{}", values.join("\0")).as_str())?
+            .result()
+            .code();
+
+        Some(base64::encode_config(
+            &signature[..16],
+            base64::URL_SAFE_NO_PAD,
+        ))
+    }
+
+    // The current state of the user’s password.
+    fn current_state(&self, login: &str, ldap: &LDAP) -> Result<String, ldap::Error> {
+        let attrs = ldap.get_user_attrs(login, self.opts.password_state_attributes.clone())?;
+
+        Ok(self.password_state(&attrs).unwrap_or_default())
+    }
+
+    // Tokens are made of the base64 encoded
+    // `<expiration>:<nonce>:<state>:<login>` payload and its signature,
+    // separated by a dot.
+    fn token(&self, login: &str, state: &str) -> Option<String> {
+        let now = Utc::now();
+        let expires = now.timestamp() + self.opts.token_lifetime as i64;
+
+        let payload = format!("{}:{}:{}:{}", expires, now.timestamp_nanos(), state, login);
+        let payload = base64::encode_config(&payload, base64::URL_SAFE_NO_PAD);
+
+        let signature = self.sign(payload.as_str())?.result().code();
+
+        Some(format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    // The claims of a valid token, whose password state must still be
+    // checked.
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+        self.sign(payload)?.verify(&signature).ok()?;
+
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let payload = String::from_utf8(payload).ok()?;
+
+        let mut parts = payload.splitn(4, ':');
+        let expires: i64 = parts.next()?.parse().ok()?;
+        let _nonce = parts.next()?;
+        let state = parts.next()?;
+        let login = parts.next()?;
+
+        if expires <= Utc::now().timestamp() {
+            return None;
+        }
+
+        Some(Claims {
+            login: login.to_string(),
+            expires,
+            state: state.to_string(),
+        })
+    }
+
+    // Verify `token` and check the user’s password didn’t change since it
+    // was issued.
+    fn check(&self, token: &str, ldap: &LDAP) -> Result<Option<Claims>, ldap::Error> {
+        let claims = match self.verify(token) {
+            Some(claims) => claims,
+            None => return Ok(None),
+        };
+
+        match self.current_state(claims.login.as_str(), ldap) {
+            Ok(state) if state == claims.state => Ok(Some(claims)),
+            Ok(_) | Err(ldap::Error::UserNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Mark `token` as used, returns false if it already was.
+    fn consume(&self, token: &str, expires: i64) -> bool {
+        let now = Utc::now().timestamp();
+
+        let mut used_tokens = self.used_tokens.lock().unwrap_or_else(|e| e.into_inner());
+        used_tokens.retain(|_, expires| *expires > now);
+
+        used_tokens.insert(token.to_string(), expires).is_none()
+    }
+
+    // Make `token` usable again, when setting the password failed.
+    fn release(&self, token: &str) {
+        self.used_tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(token);
+    }
+
+    fn link(&self, token: &str) -> Option<Url> {
+        let mut url = self.opts.url.as_ref()?.join("reset/confirm").ok()?;
+        url.query_pairs_mut().append_pair("token", token);
+
+        Some(url)
+    }
+
+    // Look the user up and queue an email with a reset link.
+    fn send(&self, login: &str, ldap: &LDAP, mailer: &Mailer) -> Result<(), SendError> {
+        let mut attrs = self.opts.password_state_attributes.clone();
+        attrs.push(self.opts.mail_attribute.clone());
+
+        let attrs = ldap.get_user_attrs(login, attrs)?;
+
+        let to = attrs
+            .iter()
+            .find(|(attr, _)| attr.eq_ignore_ascii_case(&self.opts.mail_attribute))
+            .and_then(|(_, value)| match value {
+                Value::String(value) => value.split(',').next().map(|v| v.to_string()),
+                Value::Array(values) => values.first()?.as_str().map(|v| v.to_string()),
+                _ => None,
+            })
+            .filter(|to| !to.is_empty())
+            .ok_or_else(|| SendError::MissingMail(self.opts.mail_attribute.clone()))?;
+
+        if !self
+            .opts
+            .password_state_attributes
+            .iter()
+            .any(|name| attrs.keys().any(|attr| attr.eq_ignore_ascii_case(name)))
+        {
+            warn!(
+                "None of the password state attributes of {} can be read, its reset link can be \
+                 used again on another instance or after a restart",
+                login
+            );
+        }
+
+        let link = self
+            .password_state(&attrs)
+            .and_then(|state| self.token(login, state.as_str()))
+            .and_then(|token| self.link(token.as_str()))
+            .ok_or(SendError::InvalidLink)?;
+
+        let body = format!(
+            "Someone, hopefully you, asked to reset the password of your account.\n\n\
+             Follow this link to choose a new password, it is valid for {} minutes:\n\n\
+             {}\n\n\
+             If you didn’t ask for it, you can safely ignore this email.\n",
+            self.opts.token_lifetime / 60,
+            link
+        );
+
+        mailer.queue(to.as_str(), "Reset your password", body.as_str())?;
+
+        Ok(())
+    }
+}
+
+#[derive(FromForm)]
+pub struct ResetForm {
+    login: String,
+    csrf_token: csrf::FormToken,
+}
+
+#[derive(FromForm)]
+pub struct ConfirmForm {
+    token: String,
+    new_password: String,
+    new_password_confirm: String,
+    csrf_token: csrf::FormToken,
+}
+
+const INVALID_TOKEN: &str = "This password reset link is invalid or has expired.";
+
+fn render_template(
+    csrf_token: &str,
+    form_error: Option<&str>,
+    form_success: Option<&str>,
+) -> Template {
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+
+    if let Some(form_error) = form_error {
+        context.insert("form_error".to_string(), form_error.to_string());
+    }
+
+    if let Some(form_success) = form_success {
+        context.insert("form_success".to_string(), form_success.to_string());
+    }
+
+    Template::render("reset", &context)
+}
+
+fn render_confirm_template(
+    csrf_token: &str,
+    token: &str,
+    form_error: Option<String>,
+    form_success: Option<String>,
+) -> Template {
+    let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf_token.to_string());
+    context.insert("token".to_string(), token.to_string());
+
+    if let Some(form_error) = form_error {
+        context.insert("form_error".to_string(), form_error);
+    }
+
+    if let Some(form_success) = form_success {
+        context.insert("form_success".to_string(), form_success);
+    }
+
+    Template::render("reset-confirm", &context)
+}
+
+#[get("/reset")]
+pub fn reset(csrf_token: csrf::Token, reset: State<Reset>) -> Response {
+    if !reset.enabled() {
+        return Response::Status(Status::NotFound);
+    }
+
+    Response::Template(render_template(csrf_token.as_str(), None, None))
+}
+
+// The response is the same whether the account exists or not, and whether
+// the email could be sent or not. Emails are sent in the background so the
+// response time doesn’t depend on it either.
+#[post("/reset", data = "<form>")]
+pub fn post_reset(
+    form: Form<ResetForm>,
+    csrf_token: csrf::Token,
+    client: Client,
+    reset: State<Reset>,
+    ldap: State<LDAP>,
+    mailer: State<Mailer>,
+    audit: State<Audit>,
+) -> Response {
+    if !reset.enabled() {
+        return Response::Status(Status::NotFound);
+    }
+
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    if form.login.is_empty() {
+        return Response::Template(render_template(csrf_token.as_str(), None, None));
+    }
+
+    let audit_event = |outcome: audit::Outcome| {
+        audit::Event::new(audit::Kind::PasswordResetRequest, outcome)
+            .source(client.ip, client.user_agent.as_deref())
+            .login(form.login.as_str())
+    };
+
+    if !reset.allow(form.login.as_str(), client.ip) {
+        info!(
+            "Password reset requests for {} from {} are rate limited",
+            form.login, client.ip
+        );
+        audit.record(audit_event(audit::Outcome::Failure).reason("rate_limited"));
+    } else if let Err(e) = reset.send(form.login.as_str(), &ldap, &mailer) {
+        info!(
+            "Unable to send password reset link to {}: {}",
+            form.login, e
+        );
+        audit.record(audit_event(audit::Outcome::Failure).reason(e.reason()));
+    } else {
+        info!("Queued password reset link for {}", form.login);
+        audit.record(audit_event(audit::Outcome::Success));
+    }
+
+    Response::Template(render_template(
+        csrf_token.as_str(),
+        None,
+        Some(
+            "If an account matches, you will receive an email with a link to reset your password.",
+        ),
+    ))
+}
+
+#[get("/reset/confirm?<token>")]
+pub fn confirm(
+    token: String,
+    csrf_token: csrf::Token,
+    reset: State<Reset>,
+    ldap: State<LDAP>,
+) -> Response {
+    if !reset.enabled() {
+        return Response::Status(Status::NotFound);
+    }
+
+    match reset.check(token.as_str(), &ldap) {
+        Ok(Some(_)) => Response::Template(render_confirm_template(
+            csrf_token.as_str(),
+            token.as_str(),
+            None,
+            None,
+        )),
+        Ok(None) => Response::Template(render_template(
+            csrf_token.as_str(),
+            Some(INVALID_TOKEN),
+            None,
+        )),
+        Err(e) => {
+            warn!("Unable to check password reset token: {}", e);
+            Response::Status(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/reset/confirm", data = "<form>")]
+pub fn post_confirm(
+    form: Form<ConfirmForm>,
+    csrf_token: csrf::Token,
+    client: Client,
+    reset: State<Reset>,
+    password_opts: State<password::Opts>,
+    ldap: State<LDAP>,
+    audit: State<Audit>,
+) -> Response {
+    if !reset.enabled() {
+        return Response::Status(Status::NotFound);
+    }
+
+    if !csrf_token.check(&form.csrf_token) {
+        return Response::Status(Status::Forbidden);
+    }
+
+    let Claims { login, expires, .. } = match reset.check(form.token.as_str(), &ldap) {
+        Ok(Some(claims)) => claims,
+        Ok(None) => {
+            return Response::Template(render_template(
+                csrf_token.as_str(),
+                Some(INVALID_TOKEN),
+                None,
+            ))
+        }
+        Err(e) => {
+            warn!("Unable to check password reset token: {}", e);
+            return Response::Status(Status::InternalServerError);
+        }
+    };
+
+    if let Some(message) = password_opts.check(
+        login.as_str(),
+        None,
+        form.new_password.as_str(),
+        form.new_password_confirm.as_str(),
+    ) {
+        return Response::Template(render_confirm_template(
+            csrf_token.as_str(),
+            form.token.as_str(),
+            Some(message),
+            None,
+        ));
+    }
+
+    if !reset.consume(form.token.as_str(), expires) {
+        return Response::Template(render_template(
+            csrf_token.as_str(),
+            Some(INVALID_TOKEN),
+            None,
+        ));
+    }
+
+    let audit_event = |outcome: audit::Outcome| {
+        audit::Event::new(audit::Kind::PasswordReset, outcome)
+            .source(client.ip, client.user_agent.as_deref())
+            .login(login.as_str())
+    };
+
+    match ldap.set_password(login.as_str(), form.new_password.as_str()) {
+        Ok(()) => {
+            info!("Reset password of {}", login);
+            audit.record(audit_event(audit::Outcome::Success));
+            Response::Template(render_confirm_template(
+                csrf_token.as_str(),
+                "",
+                None,
+                Some("Your password has been reset, you can now log in.".to_string()),
+            ))
+        }
+        Err(ldap::Error::PasswordRejected(reason)) => {
+            reset.release(form.token.as_str());
+            audit.record(audit_event(audit::Outcome::Failure).reason("password_rejected"));
+            Response::Template(render_confirm_template(
+                csrf_token.as_str(),
+                form.token.as_str(),
+                Some(format!("The new password was rejected: {}.", reason)),
+                None,
+            ))
+        }
+        Err(e) => {
+            reset.release(form.token.as_str());
+            audit.record(audit_event(audit::Outcome::Failure).reason(e.reason()));
+            warn!("Unable to reset password of {}: {}", login, e);
+            Response::Status(Status::InternalServerError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn new_reset(token_lifetime: u64) -> Reset {
+        Reset::new(Opts {
+            secret: Some("secret".to_string()),
+            url: Some("https://idp.example.org/".parse().unwrap()),
+            token_lifetime,
+            mail_attribute: "mail".to_string(),
+            rate_limit_window: 900,
+            rate_limit_per_login: 3,
+            rate_limit_per_ip: 10,
+            password_state_attributes: vec!["pwdChangedTime".to_string()],
+        })
+    }
+
+    fn attrs(pwd_changed_time: &str) -> HashMap<String, Value> {
+        let mut attrs = HashMap::new();
+        attrs.insert("pwdChangedTime".to_string(), json!(pwd_changed_time));
+        attrs.insert("mail".to_string(), json!("jdoe@example.org"));
+        attrs
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let reset = new_reset(3600);
+        let state = reset.password_state(&attrs("20200101000000Z")).unwrap();
+        let token = reset.token("jdoe", state.as_str()).unwrap();
+
+        let claims = reset.verify(token.as_str()).unwrap();
+        assert_eq!(claims.login, "jdoe");
+        assert_eq!(claims.state, state);
+        assert!(claims.expires > Utc::now().timestamp());
+    }
+
+    #[test]
+    fn keeps_colons_in_logins() {
+        let reset = new_reset(3600);
+        let token = reset.token("corp:jdoe", "state").unwrap();
+
+        assert_eq!(reset.verify(token.as_str()).unwrap().login, "corp:jdoe");
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let reset = new_reset(0);
+        let token = reset.token("jdoe", "state").unwrap();
+
+        assert_eq!(reset.verify(token.as_str()), None);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let reset = new_reset(3600);
+        let token = reset.token("jdoe", "state").unwrap();
+        let (payload, signature) = token.split_at(token.find('.').unwrap());
+
+        let other = reset.token("admin", "state").unwrap();
+        let other_payload = &other[..other.find('.').unwrap()];
+
+        assert_eq!(
+            reset.verify(format!("{}{}", other_payload, signature).as_str()),
+            None
+        );
+        assert_eq!(reset.verify(payload), None);
+        assert_eq!(reset.verify(format!("{}.", payload).as_str()), None);
+        assert_eq!(reset.verify(""), None);
+
+        let mut other_secret = new_reset(3600);
+        other_secret.opts.secret = Some("other".to_string());
+        assert_eq!(other_secret.verify(token.as_str()), None);
+    }
+
+    #[test]
+    fn tracks_password_state() {
+        let reset = new_reset(3600);
+
+        assert_eq!(
+            reset.password_state(&attrs("20200101000000Z")),
+            reset.password_state(&attrs("20200101000000Z"))
+        );
+        assert_ne!(
+            reset.password_state(&attrs("20200101000000Z")),
+            reset.password_state(&attrs("20200102000000Z"))
+        );
+    }
+
+    #[test]
+    fn consumes_tokens_once() {
+        let reset = new_reset(3600);
+        let expires = Utc::now().timestamp() + 3600;
+
+        assert!(reset.consume("token", expires));
+        assert!(!reset.consume("token", expires));
+
+        reset.release("token");
+        assert!(reset.consume("token", expires));
+    }
+
+    #[test]
+    fn rate_limits_requests() {
+        let reset = new_reset(3600);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(reset.allow("jdoe", ip));
+        }
+        assert!(!reset.allow("JDoe", ip));
+        assert!(reset.allow("other", "192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn only_counts_allowed_requests() {
+        let reset = new_reset(3600);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(reset.allow("jdoe", ip));
+        }
+        for _ in 0..20 {
+            assert!(!reset.allow("jdoe", ip));
+        }
+
+        // Only the 3 allowed requests count towards the IP address’s limit.
+        for i in 0..7 {
+            assert!(reset.allow(format!("user{}", i).as_str(), ip));
+        }
+        assert!(!reset.allow("user7", ip));
+    }
+}
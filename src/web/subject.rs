@@ -0,0 +1,182 @@
+// Copyright 2020 Johan Fleury <jfleury@arcaik.net>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("user has no `{0}` attribute")]
+    MissingAttribute(String),
+
+    #[error("attribute `{0}` is not a valid GUID")]
+    InvalidGuid(String),
+}
+
+impl Error {
+    // Code of the error in the audit log.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::MissingAttribute(_) => "missing_subject",
+            Error::InvalidGuid(_) => "invalid_subject",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // The attribute value as returned by the LDAP server (binary attributes
+    // are base64 encoded).
+    String,
+    // A 16 bytes binary value formatted as a GUID string, the way Active
+    // Directory displays `objectGUID`.
+    Guid,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "string" => Ok(Format::String),
+            "guid" => Ok(Format::Guid),
+            _ => Err(format!("unknown subject format: {}", value)),
+        }
+    }
+}
+
+// Build the subject from the user’s attributes.
+pub fn from_attrs(
+    attrs: &HashMap<String, Value>,
+    attribute: &str,
+    format: Format,
+) -> Result<String, Error> {
+    // Multi-valued attributes may be returned as arrays, depending on their
+    // configured cardinality.
+    let value = match attrs.get(attribute).and_then(|v| match v {
+        Value::Array(values) => values.first().and_then(|v| v.as_str()),
+        v => v.as_str(),
+    }) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(Error::MissingAttribute(attribute.to_string())),
+    };
+
+    match format {
+        Format::String => Ok(value.to_string()),
+        Format::Guid => guid(value).ok_or_else(|| Error::InvalidGuid(attribute.to_string())),
+    }
+}
+
+// Derive a subject specific to `client_id`, so different clients can’t
+// correlate their users.
+pub fn pairwise(secret: &str, client_id: &str, subject: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.input(client_id.as_bytes());
+    mac.input(b"\0");
+    mac.input(subject.as_bytes());
+
+    base64::encode_config(&mac.result().code(), base64::URL_SAFE_NO_PAD)
+}
+
+// The subject attribute is always base64 encoded by the LDAP client when
+// the `guid` format is used (see `Opts::binary_attrs`).
+fn guid(value: &str) -> Option<String> {
+    let bytes = match base64::decode(value) {
+        Ok(bytes) if bytes.len() == 16 => bytes,
+        _ => return None,
+    };
+
+    // The first three fields are stored little-endian.
+    Some(format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{}",
+        bytes[3],
+        bytes[2],
+        bytes[1],
+        bytes[0],
+        bytes[5],
+        bytes[4],
+        bytes[7],
+        bytes[6],
+        bytes[8],
+        bytes[9],
+        bytes[10..]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn attrs(value: Value) -> HashMap<String, Value> {
+        let mut attrs = HashMap::new();
+        attrs.insert("objectGUID".to_string(), value);
+        attrs
+    }
+
+    #[test]
+    fn formats_guids() {
+        let bytes: Vec<u8> = (1..=16).collect();
+        let attrs = attrs(json!(base64::encode(&bytes)));
+
+        assert_eq!(
+            from_attrs(&attrs, "objectGUID", Format::Guid).unwrap(),
+            "04030201-0605-0807-090a-0b0c0d0e0f10"
+        );
+        assert_eq!(
+            from_attrs(&attrs, "objectGUID", Format::String).unwrap(),
+            base64::encode(&bytes)
+        );
+    }
+
+    #[test]
+    fn uses_first_value_of_arrays() {
+        let bytes: Vec<u8> = (1..=16).collect();
+        let attrs = attrs(json!([base64::encode(&bytes), "other"]));
+
+        assert_eq!(
+            from_attrs(&attrs, "objectGUID", Format::Guid).unwrap(),
+            "04030201-0605-0807-090a-0b0c0d0e0f10"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_guids() {
+        let attrs = attrs(json!("0123456789abcdef"));
+
+        assert!(from_attrs(&attrs, "objectGUID", Format::Guid).is_err());
+        assert!(from_attrs(&attrs, "entryUUID", Format::String).is_err());
+    }
+
+    #[test]
+    fn derives_pairwise_subjects() {
+        let subject = pairwise("secret", "client-a", "jdoe");
+
+        assert_eq!(subject, pairwise("secret", "client-a", "jdoe"));
+        assert_ne!(subject, pairwise("secret", "client-b", "jdoe"));
+        assert_ne!(subject, pairwise("other", "client-a", "jdoe"));
+        assert_ne!(
+            pairwise("secret", "client-a", "bjdoe"),
+            pairwise("secret", "client-ab", "jdoe")
+        );
+    }
+}